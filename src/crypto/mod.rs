@@ -1,30 +1,49 @@
 use openssl::rand::rand_bytes;
 use std::fmt;
+use std::ptr;
+use std::sync::atomic::{compiler_fence, Ordering};
 
 #[derive(Debug)]
 pub enum CryptoError {
     InvalidKey,
+    /// The HSM could not be reached, or failed to answer.
+    Unavailable(String),
+    /// The HSM refused to serve the request, e.g. the device isn't authorised.
+    Denied(String),
+    /// The HSM answered, but rejected the data it was given.
+    Rejected(String),
     UnableToEncrypt(String),
     UnableToDecrypt(String),
 }
 
 impl fmt::Display for CryptoError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{}",
-            match &self {
-                Self::InvalidKey => "Invalid Key",
-                _ => "Unknown",
-            }
-        )
+        match &self {
+            Self::InvalidKey => write!(f, "Invalid Key"),
+            Self::Unavailable(why) => write!(f, "HSM unavailable: {}", why),
+            Self::Denied(why) => write!(f, "HSM denied the request: {}", why),
+            Self::Rejected(why) => write!(f, "HSM rejected the request: {}", why),
+            Self::UnableToEncrypt(why) => write!(f, "Unable to encrypt: {}", why),
+            Self::UnableToDecrypt(why) => write!(f, "Unable to decrypt: {}", why),
+        }
     }
 }
 
 pub type Blob = Vec<u8>;
 pub type CryptoResult<T> = Result<T, CryptoError>;
 
+/// Overwrites key material once it is no longer needed, in a way that the
+/// compiler can't optimise away.
+pub fn wipe(blob: &mut [u8]) {
+    for byte in blob.iter_mut() {
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
 pub trait VirtualHSM {
+    /// A short name for the HSM backend, reported in the volume status.
+    fn backend(&self) -> &'static str;
     fn encrypt(&self, blob: Blob) -> CryptoResult<Blob>;
     fn decrypt(&self, blob: Blob) -> CryptoResult<Blob>;
     fn random_bytes(&self) -> CryptoResult<Blob>;
@@ -38,6 +57,10 @@ impl DummyHSM {
 }
 
 impl VirtualHSM for DummyHSM {
+    fn backend(&self) -> &'static str {
+        "dummy"
+    }
+
     fn encrypt(&self, blob: Blob) -> CryptoResult<Blob> {
         Ok(blob)
     }
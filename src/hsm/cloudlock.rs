@@ -24,7 +24,7 @@ pub struct CloudLockConfig {
 }
 
 impl CloudLockHSM {
-    pub fn from_config(config: &ConfigJson, api_version: &str) -> Result<Self, String> {
+    pub fn from_config(config: &ConfigJson, api_version: &str) -> CryptoResult<Self> {
         let uuid = &config.uuid;
         let api_endpoint = &config
             .get_api_endpoint()
@@ -43,7 +43,7 @@ impl CloudLockHSM {
         api_endpoint: &str,
         api_version: &str,
         api_root_ca_pem: Option<String>,
-    ) -> Result<Self, String> {
+    ) -> CryptoResult<Self> {
         let base_url = url::Url::parse(api_endpoint)
             .and_then(|url| {
                 url.join(&format!(
@@ -52,12 +52,13 @@ impl CloudLockHSM {
                     uuid = uuid,
                 ))
             })
-            .map_err(|_| "Unable to parse API endpoint".to_string())?;
+            .map_err(|_| CryptoError::Unavailable("Unable to parse API endpoint".to_string()))?;
 
         let cert = Self::get_cert_as_pem(&base_url, api_key, &api_root_ca_pem)?;
 
-        let cert = openssl::x509::X509::from_pem(cert.as_bytes())
-            .map_err(|_| format!("Unable to load certificate from PEM"))?;
+        let cert = openssl::x509::X509::from_pem(cert.as_bytes()).map_err(|_| {
+            CryptoError::Rejected("Unable to load certificate from PEM".to_string())
+        })?;
 
         Ok(Self {
             api_key: String::from(api_key),
@@ -67,7 +68,7 @@ impl CloudLockHSM {
         })
     }
 
-    fn build_reqwest_client(root_cert: &Option<String>) -> Result<reqwest::Client, String> {
+    fn build_reqwest_client(root_cert: &Option<String>) -> CryptoResult<reqwest::Client> {
         let mut builder = reqwest::ClientBuilder::new();
 
         if let Some(pem) = root_cert {
@@ -75,58 +76,98 @@ impl CloudLockHSM {
                 .add_root_certificate(reqwest::Certificate::from_pem(pem.as_bytes()).unwrap());
         };
 
-        let client = builder
-            .build()
-            .map_err(|why| format!("Unable to build client for API: {:?}", why))?;
+        let client = builder.build().map_err(|why| {
+            CryptoError::Unavailable(format!("Unable to build client for API: {:?}", why))
+        })?;
         Ok(client)
     }
 
+    /// Sorts a failed response into the HSM refusing us outright, the HSM
+    /// being unwell, or the HSM not liking what it was sent.
+    fn check_response(url: &url::Url, response: &reqwest::Response) -> CryptoResult<()> {
+        let status = response.status();
+        let why = format!("Request for {} returned {}", url, status);
+
+        if status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN {
+            Err(CryptoError::Denied(why))
+        } else if status.is_server_error() {
+            Err(CryptoError::Unavailable(why))
+        } else if !status.is_success() {
+            Err(CryptoError::Rejected(why))
+        } else {
+            Ok(())
+        }
+    }
+
     fn get_cert_as_pem(
         base_url: &url::Url,
         api_key: &str,
         root_cert: &Option<String>,
-    ) -> Result<String, String> {
+    ) -> CryptoResult<String> {
         let url = base_url
             .join("config")
-            .map_err(|why| format!("Unable to build URL: {:?}", why))?;
+            .map_err(|why| CryptoError::Unavailable(format!("Unable to build URL: {:?}", why)))?;
 
         let client = Self::build_reqwest_client(root_cert)?;
 
-        let response = client
+        let mut response = client
             .get(&url.to_string())
             .bearer_auth(&api_key)
             .header("User-Agent", "CloudLock v1 HSM Client")
             .send()
-            .map_err(|why| format!("Unable to do request for {}: {:?}", &url, why))?
-            .json::<CloudLockConfig>()
-            .map_err(|why| format!("Unable to deserialize response for {}: {:?}", &url, why))?;
+            .map_err(|why| {
+                CryptoError::Unavailable(format!("Unable to do request for {}: {:?}", &url, why))
+            })?;
+        Self::check_response(&url, &response)?;
+
+        let response = response.json::<CloudLockConfig>().map_err(|why| {
+            CryptoError::Unavailable(format!(
+                "Unable to deserialize response for {}: {:?}",
+                &url, why
+            ))
+        })?;
 
         Ok(response.cert_pem)
     }
 
-    fn do_request(&self, action: &str, payload: CloudLockPayload) -> Result<Blob, String> {
-        let url = self
-            .base_url
-            .join(action)
-            .map_err(|why| format!("Unable to build CloudLock URL: {:?}", why))?;
+    fn do_request(&self, action: &str, payload: CloudLockPayload) -> CryptoResult<Blob> {
+        let url = self.base_url.join(action).map_err(|why| {
+            CryptoError::Unavailable(format!("Unable to build CloudLock URL: {:?}", why))
+        })?;
 
         let client = Self::build_reqwest_client(&self.api_root_cert)?;
-        let response = client
+        let mut response = client
             .post(&url.to_string())
             .bearer_auth(&self.api_key)
             .header("User-Agent", "CloudLock v1 HSM Client")
             .json(&payload)
             .send()
-            .map_err(|why| format!("Unable to do request for {}: {:?}", &url, why))?
-            .json::<CloudLockPayload>()
-            .map_err(|why| format!("Unable to deserialize response for {}: {:?}", &url, why))?;
-
-        base64::decode(&response.data)
-            .map_err(|why| format!("Unable to decode response from Base64: {:?}", why))
+            .map_err(|why| {
+                CryptoError::Unavailable(format!("Unable to do request for {}: {:?}", &url, why))
+            })?;
+        Self::check_response(&url, &response)?;
+
+        let response = response.json::<CloudLockPayload>().map_err(|why| {
+            CryptoError::Unavailable(format!(
+                "Unable to deserialize response for {}: {:?}",
+                &url, why
+            ))
+        })?;
+
+        base64::decode(&response.data).map_err(|why| {
+            CryptoError::UnableToDecrypt(format!(
+                "Unable to decode response from Base64: {:?}",
+                why
+            ))
+        })
     }
 }
 
 impl VirtualHSM for CloudLockHSM {
+    fn backend(&self) -> &'static str {
+        "cloudlock"
+    }
+
     fn encrypt(&self, blob: Blob) -> CryptoResult<Blob> {
         let data = base64::encode(&blob);
         let mut certs = openssl::stack::Stack::new().unwrap();
@@ -148,8 +189,9 @@ impl VirtualHSM for CloudLockHSM {
     }
 
     fn decrypt(&self, blob: Blob) -> CryptoResult<Blob> {
-        let _ = openssl::pkcs7::Pkcs7::from_pem(&blob)
-            .map_err(|why| CryptoError::UnableToDecrypt(format!("{:?}", why)))?;
+        let _ = openssl::pkcs7::Pkcs7::from_pem(&blob).map_err(|why| {
+            CryptoError::UnableToDecrypt(format!("Key is not a valid PKCS#7 PEM: {:?}", why))
+        })?;
 
         self.do_request(
             "decrypt",
@@ -158,7 +200,6 @@ impl VirtualHSM for CloudLockHSM {
                     .expect("Unable to cast payload for sending"),
             },
         )
-        .map_err(|why| CryptoError::UnableToDecrypt(format!("{:?}", why)))
     }
 
     fn random_bytes(&self) -> CryptoResult<Blob> {
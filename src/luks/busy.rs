@@ -0,0 +1,72 @@
+use crate::plugin::error::{DriverError, DriverResult};
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+/// The volumes that a long-running operation, such as a clone, is working on.
+/// Those operations run without the state lock so as not to hold up every
+/// other volume for minutes, and claim their volumes here instead.
+///
+/// The set's own lock is only held inside these methods, so they can be
+/// called with or without the state lock held.
+#[derive(Default)]
+pub struct BusyVolumes {
+    names: Mutex<HashSet<String>>,
+}
+
+impl BusyVolumes {
+    /// Claims `name` until the returned guard is dropped, unless another
+    /// operation already has it.
+    pub fn claim(&self, name: &str) -> DriverResult<Claim<'_>> {
+        if !self.names.lock().unwrap().insert(name.to_string()) {
+            return Err(busy(name));
+        }
+
+        Ok(Claim {
+            volumes: self,
+            name: name.to_string(),
+        })
+    }
+
+    /// Fails if an operation has claimed `name`.
+    pub fn check(&self, name: &str) -> DriverResult<()> {
+        if self.names.lock().unwrap().contains(name) {
+            return Err(busy(name));
+        }
+
+        Ok(())
+    }
+}
+
+fn busy(name: &str) -> DriverError {
+    DriverError::Busy(format!(
+        "Volume {} is busy with another operation, try again later",
+        name
+    ))
+}
+
+/// A claim on a volume, released when dropped.
+pub struct Claim<'a> {
+    volumes: &'a BusyVolumes,
+    name: String,
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        self.volumes.names.lock().unwrap().remove(&self.name);
+    }
+}
+
+#[test]
+fn test_claim() {
+    let volumes = BusyVolumes::default();
+
+    let claim = volumes.claim("db").unwrap();
+    assert!(volumes.claim("db").is_err());
+    assert!(volumes.check("db").is_err());
+    assert!(volumes.check("web").is_ok());
+
+    drop(claim);
+    assert!(volumes.check("db").is_ok());
+    assert!(volumes.claim("db").is_ok());
+}
@@ -0,0 +1,234 @@
+use super::options::take_option;
+
+use log::{error, warn};
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+
+const DEFAULT_CIPHER: &str = "aes";
+const DEFAULT_CIPHER_MODE: &str = "xts-plain64";
+const DEFAULT_HASH: &str = "sha256";
+const DEFAULT_KEY_SIZE: usize = 256;
+const MAX_KEY_SIZE: usize = 1024;
+
+/// The dm-crypt cipher specification used when formatting a volume, in the
+/// same terms as `cryptsetup --cipher`, `--hash` and `--key-size`.
+pub struct CipherOptions {
+    pub cipher: String,
+    pub cipher_mode: String,
+    pub hash: String,
+    /// Volume key size in bits.
+    pub key_size: usize,
+}
+
+impl Default for CipherOptions {
+    fn default() -> Self {
+        Self {
+            cipher: DEFAULT_CIPHER.to_string(),
+            cipher_mode: DEFAULT_CIPHER_MODE.to_string(),
+            hash: DEFAULT_HASH.to_string(),
+            key_size: DEFAULT_KEY_SIZE,
+        }
+    }
+}
+
+impl CipherOptions {
+    /// Reads `cipher`, `cipher_mode`, `hash` and `key_size`. As with cryptsetup
+    /// the cipher may also be given in its combined form, such as
+    /// `aes-xts-plain64` or `xchacha20,aes-adiantum-plain64`.
+    pub fn from_opts(opts: &mut HashMap<String, String>) -> Result<Self, String> {
+        let defaults = Self::default();
+        let cipher_mode = opts.remove("cipher_mode");
+        let (cipher, cipher_mode) = match (opts.remove("cipher"), cipher_mode) {
+            (Some(cipher), None) => match cipher.find('-') {
+                Some(split) => (cipher[..split].to_string(), cipher[split + 1..].to_string()),
+                None => (cipher, defaults.cipher_mode),
+            },
+            (Some(cipher), Some(mode)) => {
+                if cipher.contains('-') {
+                    return Err(format!(
+                        "cipher \"{}\" already includes a mode, so cipher_mode cannot also be set",
+                        cipher
+                    ));
+                }
+                (cipher, mode)
+            }
+            (None, Some(mode)) => (defaults.cipher, mode),
+            (None, None) => (defaults.cipher, defaults.cipher_mode),
+        };
+
+        let options = Self {
+            cipher,
+            cipher_mode,
+            hash: opts.remove("hash").unwrap_or(defaults.hash),
+            key_size: take_option(opts, "key_size")?.unwrap_or(defaults.key_size),
+        };
+        options.validate()?;
+
+        Ok(options)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let is_spec_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+
+        if self.cipher.is_empty()
+            || !self
+                .cipher
+                .split(',')
+                .all(|c| !c.is_empty() && c.chars().all(is_spec_char))
+        {
+            return Err(format!("Invalid cipher \"{}\"", self.cipher));
+        }
+        if self.chain_mode().is_empty()
+            || !self
+                .cipher_mode
+                .chars()
+                .all(|c| is_spec_char(c) || c == '-' || c == ':')
+        {
+            return Err(format!("Invalid cipher_mode \"{}\"", self.cipher_mode));
+        }
+        if self.hash.is_empty() || !self.hash.chars().all(|c| is_spec_char(c) || c == '-') {
+            return Err(format!("Invalid hash \"{}\"", self.hash));
+        }
+        if self.key_size == 0 || self.key_size % 8 != 0 || self.key_size > MAX_KEY_SIZE {
+            return Err(format!(
+                "key_size must be a multiple of 8 bits no larger than {}",
+                MAX_KEY_SIZE
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// The block cipher chaining mode, i.e. `xts` for `xts-plain64`.
+    fn chain_mode(&self) -> &str {
+        self.cipher_mode.split('-').next().unwrap_or_default()
+    }
+
+    /// The hash used by an ESSIV style IV generator, i.e. `sha256` for
+    /// `cbc-essiv:sha256`.
+    fn iv_hash(&self) -> Option<&str> {
+        self.cipher_mode.splitn(2, ':').nth(1)
+    }
+
+    /// Whether the mode authenticates as well as encrypts, and so needs
+    /// dm-integrity to store its tags.
+    pub fn is_aead(&self) -> bool {
+        match self.chain_mode() {
+            "gcm" | "ccm" => true,
+            _ => self.cipher.contains("poly1305"),
+        }
+    }
+
+    /// The name the kernel crypto API knows this cipher by, such as
+    /// `xts(aes)` or `adiantum(xchacha20,aes)`.
+    pub fn kernel_cipher_name(&self) -> String {
+        format!("{}({})", self.chain_mode(), self.cipher)
+    }
+
+    /// Asks the kernel crypto API, through an `AF_ALG` socket, whether it can
+    /// provide the cipher with the requested key size and the hashes. Binding
+    /// the socket also loads any crypto modules that are needed.
+    pub fn check_kernel_support(&self) -> Result<(), String> {
+        let cipher = self.kernel_cipher_name();
+        let alg_type = if self.is_aead() { "aead" } else { "skcipher" };
+        if !kernel_supports(alg_type, &cipher, Some(self.key_size / 8)) {
+            return Err(format!(
+                "The kernel does not support the cipher {} with a {} bit key",
+                cipher, self.key_size
+            ));
+        }
+
+        let mut hashes = vec![self.hash.as_str()];
+        hashes.extend(self.iv_hash());
+        for hash in hashes {
+            if !kernel_supports("hash", hash, None) {
+                return Err(format!("The kernel does not support the hash {}", hash));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Asks the kernel crypto API whether it has an algorithm, and whether it
+/// takes a key of `key_len` bytes. Kernels built without the userspace crypto
+/// API can't be asked, so for those the algorithm is given the benefit of the
+/// doubt and left for cryptsetup to refuse. Any other failure to ask counts
+/// as no.
+pub fn kernel_supports(alg_type: &str, alg_name: &str, key_len: Option<usize>) -> bool {
+    let mut addr: libc::sockaddr_alg = unsafe { mem::zeroed() };
+    if alg_type.len() >= addr.salg_type.len() || alg_name.len() >= addr.salg_name.len() {
+        return false;
+    }
+    addr.salg_family = libc::AF_ALG as libc::sa_family_t;
+    addr.salg_type[..alg_type.len()].copy_from_slice(alg_type.as_bytes());
+    addr.salg_name[..alg_name.len()].copy_from_slice(alg_name.as_bytes());
+
+    unsafe {
+        let fd = libc::socket(libc::AF_ALG, libc::SOCK_SEQPACKET, 0);
+        if fd < 0 {
+            let why = io::Error::last_os_error();
+            if why.raw_os_error() == Some(libc::EAFNOSUPPORT) {
+                warn!(
+                    "Unable to check kernel support for {} {}: {}",
+                    alg_type, alg_name, why
+                );
+                return true;
+            }
+            error!(
+                "Unable to check kernel support for {} {}: {}",
+                alg_type, alg_name, why
+            );
+            return false;
+        }
+
+        let mut supported = libc::bind(
+            fd,
+            &addr as *const libc::sockaddr_alg as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_alg>() as libc::socklen_t,
+        ) == 0;
+
+        if let (true, Some(key_len)) = (supported, key_len) {
+            // XTS refuses keys whose two halves match, so avoid an all-zero key
+            let key: Vec<u8> = (0..key_len).map(|i| i as u8).collect();
+            supported = libc::setsockopt(
+                fd,
+                libc::SOL_ALG,
+                libc::ALG_SET_KEY,
+                key.as_ptr() as *const libc::c_void,
+                key_len as libc::socklen_t,
+            ) == 0;
+        }
+
+        libc::close(fd);
+        supported
+    }
+}
+
+#[test]
+fn test_cipher_options() {
+    let parse = |pairs: &[(&str, &str)]| CipherOptions::from_opts(&mut super::test_opts(pairs));
+
+    let adiantum = parse(&[("cipher", "xchacha20,aes-adiantum-plain64")]).unwrap();
+    assert_eq!(adiantum.cipher, "xchacha20,aes");
+    assert_eq!(adiantum.cipher_mode, "adiantum-plain64");
+    assert_eq!(adiantum.kernel_cipher_name(), "adiantum(xchacha20,aes)");
+
+    let xts = parse(&[
+        ("cipher", "aes"),
+        ("cipher_mode", "xts-plain64"),
+        ("key_size", "512"),
+    ])
+    .unwrap();
+    assert_eq!(xts.kernel_cipher_name(), "xts(aes)");
+    assert_eq!(xts.key_size, 512);
+
+    let essiv = parse(&[("cipher_mode", "cbc-essiv:sha256")]).unwrap();
+    assert_eq!(essiv.kernel_cipher_name(), "cbc(aes)");
+    assert_eq!(essiv.iv_hash(), Some("sha256"));
+
+    assert!(parse(&[("cipher", "aes-xts-plain64"), ("cipher_mode", "cbc")]).is_err());
+    assert!(parse(&[("cipher", "aes;rm")]).is_err());
+    assert!(parse(&[("key_size", "250")]).is_err());
+}
@@ -0,0 +1,153 @@
+use super::host;
+
+use log::warn;
+use openssl::rand::rand_bytes;
+use std::cmp;
+use std::fs;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process::Command;
+
+/// The LUKS2 header, keyslots included, takes up the first 16 MiB of the
+/// image by default, which is what is overwritten if the header can't be read.
+const HEADER_AREA: u64 = 16 << 20;
+const SECTOR_SIZE: u64 = 512;
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// Destroys every keyslot in the LUKS header of `image` and then overwrites
+/// the whole header area, so that the data can never be decrypted again,
+/// even by someone holding the key.
+pub fn erase_header(image: &Path) -> Result<(), String> {
+    let len = fs::metadata(image)
+        .map_err(|why| format!("Unable to read {}: {}", image.display(), why))?
+        .len();
+    let area = header_area(image, len);
+
+    if let Err(why) = host::run(
+        Command::new("cryptsetup")
+            .arg("erase")
+            .arg("--batch-mode")
+            .arg(image),
+        None,
+    ) {
+        // Overwriting the header below destroys the keyslots all the same.
+        warn!(
+            "Unable to erase the keyslots of {}: {}",
+            image.display(),
+            why
+        );
+    }
+
+    overwrite(image, cmp::min(len, area)).map_err(|why| {
+        format!(
+            "Unable to overwrite the header of {}: {}",
+            image.display(),
+            why
+        )
+    })
+}
+
+/// How much of `image` the LUKS header takes up, which is everything before
+/// the data. A detached header has its data elsewhere, so it is all header.
+fn header_area(image: &Path, len: u64) -> u64 {
+    let dump = host::run(Command::new("cryptsetup").arg("luksDump").arg(image), None);
+    match dump.map(|dump| data_offset(&dump)) {
+        Ok(Some(0)) => len,
+        Ok(Some(offset)) => offset,
+        Ok(None) => {
+            warn!("Unable to find the data offset of {}", image.display());
+            HEADER_AREA
+        }
+        Err(why) => {
+            warn!(
+                "Unable to read the LUKS header of {}: {}",
+                image.display(),
+                why
+            );
+            HEADER_AREA
+        }
+    }
+}
+
+/// The offset of the data in bytes, from the output of `cryptsetup luksDump`.
+/// LUKS1 gives it in sectors as the payload offset, and LUKS2 in bytes for
+/// each data segment.
+fn data_offset(dump: &str) -> Option<u64> {
+    let mut in_segments = false;
+    for line in dump.lines() {
+        if !line.starts_with(char::is_whitespace) && line.ends_with(':') {
+            in_segments = line == "Data segments:";
+        }
+        let line = line.trim();
+        if line.starts_with("Payload offset:") {
+            let sectors = line["Payload offset:".len()..].trim().parse::<u64>().ok()?;
+            return sectors.checked_mul(SECTOR_SIZE);
+        }
+        if in_segments && line.starts_with("offset:") {
+            return line["offset:".len()..]
+                .split_whitespace()
+                .next()
+                .and_then(|bytes| bytes.parse().ok());
+        }
+    }
+
+    None
+}
+
+/// Overwrites the whole of a file with random data.
+pub fn shred(path: &Path) -> Result<(), String> {
+    let len = fs::metadata(path)
+        .map_err(|why| format!("Unable to read {}: {}", path.display(), why))?
+        .len();
+
+    overwrite(path, len).map_err(|why| format!("Unable to shred {}: {}", path.display(), why))
+}
+
+/// Overwrites a file with random data and then deletes it.
+pub fn shred_and_remove(path: &Path) -> Result<(), String> {
+    shred(path)?;
+
+    fs::remove_file(path).map_err(|why| format!("Unable to remove {}: {}", path.display(), why))
+}
+
+fn overwrite(path: &Path, len: u64) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new().write(true).open(path)?;
+    file.seek(SeekFrom::Start(0))?;
+
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut remaining = len;
+    while remaining > 0 {
+        let chunk = cmp::min(remaining, CHUNK_SIZE as u64) as usize;
+        rand_bytes(&mut buf[..chunk]).map_err(|why| io::Error::new(io::ErrorKind::Other, why))?;
+        file.write_all(&buf[..chunk])?;
+        remaining -= chunk as u64;
+    }
+
+    file.sync_all()
+}
+
+#[test]
+fn test_data_offset() {
+    let luks1 = "LUKS header information for volume.img\n\n\
+                 Version:       \t1\n\
+                 Cipher name:   \taes\n\
+                 Payload offset:\t4096\n\
+                 MK bits:       \t512\n";
+    assert_eq!(data_offset(luks1), Some(4096 * 512));
+
+    let luks2 = "LUKS header information\n\
+                 Version:       \t2\n\
+                 Metadata area: \t16384 [bytes]\n\n\
+                 Data segments:\n  0: crypt\n\toffset: 33554432 [bytes]\n\
+                 \tlength: (whole device)\n\n\
+                 Keyslots:\n  0: luks2\n\tArea offset:32768 [bytes]\n";
+    assert_eq!(data_offset(luks2), Some(32 << 20));
+
+    let keyslots_first = "Keyslots:\n  0: luks2\n\toffset: 32768 [bytes]\n\
+                          Data segments:\n  0: crypt\n\toffset: 0 [bytes]\n";
+    assert_eq!(data_offset(keyslots_first), Some(0));
+    assert_eq!(
+        data_offset("Device volume.img is not a valid LUKS device.\n"),
+        None
+    );
+}
@@ -0,0 +1,437 @@
+use super::host;
+use super::options::take_option;
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+
+const FS_BLOCK_SIZE: u64 = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilesystemType {
+    Ext4,
+    Xfs,
+    Btrfs,
+}
+
+impl FilesystemType {
+    /// Reads the filesystem type from the superblock on `device`.
+    pub fn detect(device: &Path) -> Result<Self, String> {
+        let output = host::run(
+            Command::new("blkid")
+                .arg("-o")
+                .arg("value")
+                .arg("-s")
+                .arg("TYPE")
+                .arg(device),
+            None,
+        )?;
+
+        output.trim().parse()
+    }
+
+    /// XFS and btrfs can only be grown while they are mounted.
+    pub fn grows_mounted(self) -> bool {
+        self != Self::Ext4
+    }
+
+    /// Grows the filesystem on `device` to fill it. XFS and btrfs are grown
+    /// through their `mountpoint`, ext4 through the device.
+    pub fn grow(self, device: &Path, mountpoint: &Path) -> Result<(), String> {
+        let mut command;
+        match self {
+            Self::Ext4 => {
+                command = Command::new("resize2fs");
+                command.arg(device);
+            }
+            Self::Xfs => {
+                command = Command::new("xfs_growfs");
+                command.arg(mountpoint);
+            }
+            Self::Btrfs => {
+                command = Command::new("btrfs");
+                command
+                    .arg("filesystem")
+                    .arg("resize")
+                    .arg("max")
+                    .arg(mountpoint);
+            }
+        }
+
+        host::run(&mut command, None)
+            .map(|_| ())
+            .map_err(|why| format!("Unable to grow the {} filesystem: {}", self, why))
+    }
+
+    /// Shrinks the unmounted filesystem on `device` to `size` bytes. Only
+    /// ext4 can shrink.
+    pub fn shrink(self, device: &Path, size: u64) -> Result<(), String> {
+        if self != Self::Ext4 {
+            return Err(format!("{} filesystems can't be shrunk", self));
+        }
+
+        check(device)?;
+        host::run(
+            Command::new("resize2fs")
+                .arg(device)
+                .arg(format!("{}K", size / 1024)),
+            None,
+        )
+        .map(|_| ())
+        .map_err(|why| format!("Unable to shrink the {} filesystem: {}", self, why))
+    }
+
+    /// Gives the unmounted filesystem on `device` a new UUID, so that a copy
+    /// can be mounted alongside the original. XFS in particular refuses to
+    /// mount a second filesystem with the same UUID.
+    pub fn regenerate_uuid(self, device: &Path) -> Result<(), String> {
+        let mut command;
+        match self {
+            Self::Ext4 => {
+                // tune2fs won't touch a filesystem whose journal needs replaying.
+                check(device)?;
+                command = Command::new("tune2fs");
+                command.arg("-U").arg("random").arg(device);
+            }
+            Self::Xfs => {
+                command = Command::new("xfs_admin");
+                command.arg("-U").arg("generate").arg(device);
+            }
+            Self::Btrfs => {
+                command = Command::new("btrfstune");
+                command.arg("-f").arg("-u").arg(device);
+            }
+        }
+
+        host::run(&mut command, None)
+            .map(|_| ())
+            .map_err(|why| format!("Unable to change the {} filesystem UUID: {}", self, why))
+    }
+
+    fn max_label_len(self) -> usize {
+        match self {
+            Self::Ext4 => 16,
+            Self::Xfs => 12,
+            Self::Btrfs => 255,
+        }
+    }
+}
+
+/// Checks and repairs an unmounted ext4 filesystem. e2fsck exits with 1 when
+/// it has fixed something, which is still a success.
+fn check(device: &Path) -> Result<(), String> {
+    host::run_accepting(
+        Command::new("e2fsck").arg("-f").arg("-y").arg(device),
+        None,
+        &[0, 1],
+    )
+    .map(|_| ())
+    .map_err(|why| format!("Unable to check the filesystem: {}", why))
+}
+
+impl FromStr for FilesystemType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ext4" => Ok(Self::Ext4),
+            "xfs" => Ok(Self::Xfs),
+            "btrfs" => Ok(Self::Btrfs),
+            _ => Err(format!(
+                "Unsupported filesystem \"{}\", expected one of ext4, xfs or btrfs",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for FilesystemType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Ext4 => "ext4",
+                Self::Xfs => "xfs",
+                Self::Btrfs => "btrfs",
+            }
+        )
+    }
+}
+
+/// The filesystem to create inside the LUKS container, along with the mkfs
+/// tunables that were asked for. Stride and stripe width are expressed in 4K
+/// filesystem blocks for both ext4 and XFS.
+pub struct FilesystemOptions {
+    pub fs_type: FilesystemType,
+    pub label: Option<String>,
+    pub inode_size: Option<u64>,
+    pub reserved_percentage: Option<u8>,
+    pub stride: Option<u64>,
+    pub stripe_width: Option<u64>,
+    /// Owner, group and permissions for the root directory of the new
+    /// filesystem.
+    pub root_uid: Option<u32>,
+    pub root_gid: Option<u32>,
+    pub root_mode: Option<u32>,
+}
+
+impl Default for FilesystemOptions {
+    fn default() -> Self {
+        Self {
+            fs_type: FilesystemType::Ext4,
+            label: None,
+            inode_size: None,
+            reserved_percentage: None,
+            stride: None,
+            stripe_width: None,
+            root_uid: None,
+            root_gid: None,
+            root_mode: None,
+        }
+    }
+}
+
+impl FilesystemOptions {
+    pub fn from_opts(opts: &mut HashMap<String, String>) -> Result<Self, String> {
+        let options = Self {
+            fs_type: take_option(opts, "fs")?.unwrap_or(FilesystemType::Ext4),
+            label: opts.remove("fs_label"),
+            inode_size: take_option(opts, "inode_size")?,
+            reserved_percentage: take_option(opts, "reserved_percentage")?,
+            stride: take_option(opts, "stride")?,
+            stripe_width: take_option(opts, "stripe_width")?,
+            root_uid: take_option(opts, "uid")?,
+            root_gid: take_option(opts, "gid")?,
+            root_mode: match opts.remove("mode") {
+                Some(mode) => Some(parse_mode(&mode)?),
+                None => None,
+            },
+        };
+        options.validate()?;
+
+        Ok(options)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let fs_type = self.fs_type;
+
+        if let Some(label) = &self.label {
+            if label.is_empty() || label.len() > fs_type.max_label_len() {
+                return Err(format!(
+                    "fs_label must be between 1 and {} characters for {}",
+                    fs_type.max_label_len(),
+                    fs_type
+                ));
+            }
+            if !label
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+            {
+                return Err(format!(
+                    "fs_label \"{}\" contains invalid characters",
+                    label
+                ));
+            }
+        }
+
+        if let Some(inode_size) = self.inode_size {
+            let (min, max) = match fs_type {
+                FilesystemType::Ext4 => (128, FS_BLOCK_SIZE),
+                FilesystemType::Xfs => (256, 2048),
+                FilesystemType::Btrfs => {
+                    return Err("inode_size is not supported for btrfs".to_string())
+                }
+            };
+            if !inode_size.is_power_of_two() || inode_size < min || inode_size > max {
+                return Err(format!(
+                    "inode_size for {} must be a power of two between {} and {}",
+                    fs_type, min, max
+                ));
+            }
+        }
+
+        if let Some(reserved) = self.reserved_percentage {
+            if fs_type != FilesystemType::Ext4 {
+                return Err(format!(
+                    "reserved_percentage is not supported for {}",
+                    fs_type
+                ));
+            }
+            if reserved > 50 {
+                return Err("reserved_percentage must be between 0 and 50".to_string());
+            }
+        }
+
+        match (self.stride, self.stripe_width) {
+            (None, None) => {}
+            _ if fs_type == FilesystemType::Btrfs => {
+                return Err("stride and stripe_width are not supported for btrfs".to_string())
+            }
+            (Some(0), _) | (_, Some(0)) => {
+                return Err("stride and stripe_width must be greater than zero".to_string())
+            }
+            (Some(stride), Some(stripe_width)) if stripe_width % stride != 0 => {
+                return Err("stripe_width must be a multiple of stride".to_string())
+            }
+            (Some(_), Some(_)) => {}
+            _ if fs_type == FilesystemType::Xfs => {
+                return Err("stride and stripe_width must be given together for xfs".to_string())
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn mkfs_command(&self, device: &Path) -> Command {
+        let mut command;
+        match self.fs_type {
+            FilesystemType::Ext4 => {
+                command = Command::new("mkfs.ext4");
+                command
+                    .arg("-q")
+                    .arg("-I")
+                    .arg(self.inode_size.unwrap_or(256).to_string())
+                    .arg("-m")
+                    .arg(self.reserved_percentage.unwrap_or(5).to_string());
+
+                let mut extended = vec![];
+                if let Some(stride) = self.stride {
+                    extended.push(format!("stride={}", stride));
+                }
+                if let Some(stripe_width) = self.stripe_width {
+                    extended.push(format!("stripe_width={}", stripe_width));
+                }
+                if !extended.is_empty() {
+                    command.arg("-E").arg(extended.join(","));
+                }
+            }
+            FilesystemType::Xfs => {
+                command = Command::new("mkfs.xfs");
+                command.arg("-q");
+
+                if let Some(inode_size) = self.inode_size {
+                    command.arg("-i").arg(format!("size={}", inode_size));
+                }
+                if let (Some(stride), Some(stripe_width)) = (self.stride, self.stripe_width) {
+                    command.arg("-d").arg(format!(
+                        "su={},sw={}",
+                        stride * FS_BLOCK_SIZE,
+                        stripe_width / stride
+                    ));
+                }
+            }
+            FilesystemType::Btrfs => {
+                command = Command::new("mkfs.btrfs");
+                command.arg("-q");
+            }
+        }
+
+        if let Some(label) = &self.label {
+            command.arg("-L").arg(label);
+        }
+        command.arg(device);
+
+        command
+    }
+
+    /// Whether the root of the new filesystem needs its ownership or
+    /// permissions changing.
+    pub fn has_root_options(&self) -> bool {
+        self.root_uid.is_some() || self.root_gid.is_some() || self.root_mode.is_some()
+    }
+
+    /// Applies the root ownership and permissions to the new filesystem,
+    /// mounted at `root`.
+    pub fn set_up_root(&self, root: &Path) -> Result<(), String> {
+        if self.root_uid.is_some() || self.root_gid.is_some() {
+            let c_root = CString::new(root.as_os_str().as_bytes())
+                .map_err(|why| format!("Invalid path {}: {}", root.display(), why))?;
+            // -1 leaves the owner or group as it is.
+            let uid = self.root_uid.unwrap_or(u32::max_value());
+            let gid = self.root_gid.unwrap_or(u32::max_value());
+            if unsafe { libc::chown(c_root.as_ptr(), uid, gid) } != 0 {
+                return Err(format!(
+                    "Unable to change the owner of {}: {}",
+                    root.display(),
+                    io::Error::last_os_error()
+                ));
+            }
+        }
+
+        if let Some(mode) = self.root_mode {
+            fs::set_permissions(root, fs::Permissions::from_mode(mode)).map_err(|why| {
+                format!(
+                    "Unable to change the permissions of {}: {}",
+                    root.display(),
+                    why
+                )
+            })?;
+        }
+
+        Ok(())
+    }
+
+    pub fn format_device(&self, device: &Path) -> Result<(), String> {
+        let output = self.mkfs_command(device).output().map_err(|why| {
+            format!(
+                "Unable to run mkfs for {} on {}: {}",
+                self.fs_type,
+                device.display(),
+                why
+            )
+        })?;
+
+        if !output.status.success() {
+            return Err(format!(
+                "mkfs for {} on {} failed ({}): {}",
+                self.fs_type,
+                device.display(),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses permissions given in octal, such as `0755` or `2775`.
+fn parse_mode(value: &str) -> Result<u32, String> {
+    match u32::from_str_radix(value, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!(
+            "Invalid value \"{}\" for option mode: expected octal permissions such as 0755",
+            value
+        )),
+    }
+}
+
+#[test]
+fn test_filesystem_options_validation() {
+    let parse = |pairs: &[(&str, &str)]| FilesystemOptions::from_opts(&mut super::test_opts(pairs));
+
+    assert!(parse(&[]).is_ok());
+    assert!(parse(&[("fs", "xfs"), ("inode_size", "512"), ("fs_label", "db")]).is_ok());
+    assert!(parse(&[("fs", "ext4"), ("stride", "16"), ("stripe_width", "64")]).is_ok());
+    assert!(parse(&[("fs", "btrfs"), ("fs_label", "build-cache")]).is_ok());
+    let root = parse(&[("uid", "1000"), ("gid", "1000"), ("mode", "2775")]).unwrap();
+    assert_eq!(root.root_mode, Some(0o2775));
+
+    assert!(parse(&[("fs", "zfs")]).is_err());
+    assert!(parse(&[("fs", "btrfs"), ("inode_size", "256")]).is_err());
+    assert!(parse(&[("fs", "xfs"), ("reserved_percentage", "5")]).is_err());
+    assert!(parse(&[("fs", "xfs"), ("stride", "16")]).is_err());
+    assert!(parse(&[("fs", "ext4"), ("stride", "16"), ("stripe_width", "40")]).is_err());
+    assert!(parse(&[("fs", "xfs"), ("fs_label", "much-too-long-label")]).is_err());
+    assert!(parse(&[("mode", "0999")]).is_err());
+    assert!(parse(&[("uid", "-1")]).is_err());
+}
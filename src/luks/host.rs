@@ -0,0 +1,291 @@
+use crate::plugin::name::{validate_name, NameError};
+
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+const MAPPER_DIR: &str = "/dev/mapper";
+const MAX_MAPPER_NAME_LEN: usize = 127;
+const COPY_CHUNK_SIZE: usize = 1 << 20;
+/// `_IOW(0x94, 9, int)`, which makes a file share the extents of another.
+const FICLONE: libc::c_ulong = 0x4004_9409;
+
+/// Returns every mountpoint currently listed in `/proc/self/mountinfo`.
+pub fn mounted_paths() -> Result<HashSet<PathBuf>, String> {
+    let mountinfo = fs::read_to_string(MOUNTINFO)
+        .map_err(|why| format!("Unable to read {}: {}", MOUNTINFO, why))?;
+
+    Ok(mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(|mountpoint| PathBuf::from(unescape_mountinfo(mountpoint)))
+        .collect())
+}
+
+/// Returns the names of all device-mapper devices, i.e. the entries of
+/// `/dev/mapper` other than the `control` node.
+pub fn active_mappers() -> Result<HashSet<String>, String> {
+    let entries = fs::read_dir(MAPPER_DIR)
+        .map_err(|why| format!("Unable to read {}: {}", MAPPER_DIR, why))?;
+
+    Ok(entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name != "control")
+        .collect())
+}
+
+/// Returns the number of bytes in use on the filesystem mounted at `path`.
+pub fn used_bytes(path: &Path) -> Result<u64, String> {
+    let stat = statvfs(path)?;
+
+    Ok((stat.f_blocks - stat.f_bfree) as u64 * stat.f_frsize as u64)
+}
+
+/// Returns the number of bytes free for unprivileged use on the filesystem
+/// holding `path`.
+pub fn available_bytes(path: &Path) -> Result<u64, String> {
+    let stat = statvfs(path)?;
+
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+fn statvfs(path: &Path) -> Result<libc::statvfs, String> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|why| format!("Invalid path {}: {}", path.display(), why))?;
+
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(format!(
+            "Unable to stat filesystem at {}: {}",
+            path.display(),
+            io::Error::last_os_error()
+        ));
+    }
+
+    Ok(stat)
+}
+
+/// Reserves `len` bytes of disk for a file, so that writing to it later can
+/// never run out of space.
+pub fn fallocate(file: &fs::File, len: u64) -> io::Result<()> {
+    if unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len as libc::off_t) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Writes a file by way of a temporary file that is synced and renamed into
+/// place, so a crash part way through never leaves a truncated file behind.
+/// The file is only readable by its owner.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+
+    match path.parent() {
+        Some(dir) => sync_dir(dir),
+        None => Ok(()),
+    }
+}
+
+/// Copies `from` to a new file at `to`, readable only by root. A reflink is
+/// tried first, which on btrfs or XFS shares the extents of the original
+/// until either is written. Otherwise the data is streamed, and runs of
+/// zeros are skipped so that a sparse file stays sparse.
+pub fn copy_file(from: &Path, to: &Path) -> io::Result<()> {
+    let mut source = fs::File::open(from)?;
+    let mut target = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(to)?;
+
+    if unsafe { libc::ioctl(target.as_raw_fd(), FICLONE, source.as_raw_fd()) } != 0 {
+        let len = source.metadata()?.len();
+        let mut buf = vec![0; COPY_CHUNK_SIZE];
+        loop {
+            let read = source.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            if buf[..read].iter().all(|&b| b == 0) {
+                target.seek(SeekFrom::Current(read as i64))?;
+            } else {
+                target.write_all(&buf[..read])?;
+            }
+        }
+        target.set_len(len)?;
+    }
+    target.sync_all()?;
+
+    match to.parent() {
+        Some(dir) => sync_dir(dir),
+        None => Ok(()),
+    }
+}
+
+/// Freezes the filesystem mounted at `mountpoint`, or thaws it again. While
+/// frozen it is consistent on disk and every write to it blocks.
+pub fn fsfreeze(mountpoint: &Path, freeze: bool) -> Result<(), String> {
+    run(
+        Command::new("fsfreeze")
+            .arg(if freeze { "--freeze" } else { "--unfreeze" })
+            .arg(mountpoint),
+        None,
+    )
+    .map(|_| ())
+}
+
+/// Makes renames and new entries in a directory durable.
+pub fn sync_dir(dir: &Path) -> io::Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
+/// Runs a host tool to completion, optionally feeding it `input` on stdin,
+/// and returns its stdout. A non-zero exit is an error carrying its stderr.
+pub fn run(command: &mut Command, input: Option<&[u8]>) -> Result<String, String> {
+    run_accepting(command, input, &[0])
+}
+
+/// Like `run`, for tools such as `e2fsck` that also exit with other codes
+/// when they succeed.
+pub fn run_accepting(
+    command: &mut Command,
+    input: Option<&[u8]>,
+    codes: &[i32],
+) -> Result<String, String> {
+    let describe = || format!("{:?}", command);
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|why| format!("Unable to run {}: {}", describe(), why))?;
+
+    if let Some(input) = input {
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin
+                .write_all(input)
+                .map_err(|why| format!("Unable to write to {}: {}", describe(), why))?;
+        }
+    }
+    drop(child.stdin.take());
+
+    let output = child
+        .wait_with_output()
+        .map_err(|why| format!("Unable to run {}: {}", describe(), why))?;
+    if !output
+        .status
+        .code()
+        .map_or(false, |code| codes.contains(&code))
+    {
+        return Err(format!(
+            "{} failed ({}): {}",
+            describe(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Returns the loop device backing `image`, if it is attached to one.
+pub fn loop_device(image: &Path) -> Result<Option<PathBuf>, String> {
+    let output = run(Command::new("losetup").arg("-j").arg(image), None)?;
+
+    Ok(output
+        .lines()
+        .filter_map(|line| line.split(':').next())
+        .map(PathBuf::from)
+        .next())
+}
+
+/// Makes a loop device pick up a change in the size of its backing file.
+pub fn refresh_loop_device(device: &Path) -> Result<(), String> {
+    run(Command::new("losetup").arg("-c").arg(device), None).map(|_| ())
+}
+
+/// Returns the size of a block device in bytes.
+pub fn device_size(device: &Path) -> Result<u64, String> {
+    let output = run(
+        Command::new("blockdev").arg("--getsize64").arg(device),
+        None,
+    )?;
+
+    output
+        .trim()
+        .parse()
+        .map_err(|why| format!("Invalid size for {}: {}", device.display(), why))
+}
+
+/// Discards the unused blocks of the filesystem mounted at `mountpoint`.
+pub fn fstrim(mountpoint: &Path) -> Result<(), String> {
+    run(Command::new("fstrim").arg(mountpoint), None).map(|_| ())
+}
+
+/// Returns how many sectors the dm-integrity device beneath a LUKS2 mapping
+/// has found not to match their tags since it was opened.
+pub fn integrity_mismatches(mapper_name: &str) -> Result<u64, String> {
+    let device = format!("{}_dif", mapper_name);
+    let output = run(Command::new("dmsetup").arg("status").arg(&device), None)?;
+
+    // <start> <length> integrity <mismatches> <provided data sectors> ...
+    output
+        .split_whitespace()
+        .nth(3)
+        .ok_or_else(|| format!("Unexpected status for {}: {}", device, output.trim()))?
+        .parse()
+        .map_err(|why| format!("Invalid mismatch count for {}: {}", device, why))
+}
+
+/// Device-mapper names follow the same grammar as volume names, bounded by
+/// the kernel's name length.
+pub fn validate_mapper_name(name: &str) -> Result<(), NameError> {
+    validate_name(name, MAX_MAPPER_NAME_LEN)
+}
+
+/// mountinfo escapes space, tab, newline and backslash as octal sequences.
+fn unescape_mountinfo(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 4 <= bytes.len() {
+            let octal = std::str::from_utf8(&bytes[i + 1..i + 4]).unwrap_or_default();
+            if let Ok(c) = u8::from_str_radix(octal, 8) {
+                unescaped.push(c);
+                i += 4;
+                continue;
+            }
+        }
+        unescaped.push(bytes[i]);
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
+
+#[test]
+fn test_unescape_mountinfo() {
+    assert_eq!(unescape_mountinfo("/mnt/plain"), "/mnt/plain");
+    assert_eq!(unescape_mountinfo("/mnt/with\\040space"), "/mnt/with space");
+    assert_eq!(unescape_mountinfo("/mnt/trailing\\"), "/mnt/trailing\\");
+}
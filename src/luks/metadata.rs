@@ -0,0 +1,255 @@
+use super::host;
+use super::options::VolumeOptions;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const METADATA_FILE: &str = "volume.json";
+/// Bumped whenever the layout of `volume.json` changes. Files written by a
+/// newer driver are refused rather than misread.
+pub const METADATA_VERSION: u32 = 2;
+/// Version 2 added detached headers. Volumes without one are still written
+/// as version 1 so that older drivers can keep using them.
+const ATTACHED_HEADER_VERSION: u32 = 1;
+/// Where a detached header is kept unless the daemon says otherwise,
+/// relative to the volume directory.
+pub const HEADER_FILE: &str = "header.img";
+
+/// The LUKS parameters a volume was formatted with.
+#[derive(Serialize, Deserialize)]
+pub struct LuksMetadata {
+    pub version: String,
+    pub uuid: Option<String>,
+    pub cipher: String,
+    pub cipher_mode: String,
+    pub hash: Option<String>,
+    pub key_size: usize,
+    pub pbkdf: Option<String>,
+    /// The detached header, if the header isn't in the image. A relative
+    /// path is relative to the volume directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub header: Option<String>,
+    /// The dm-integrity mode, if the volume has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub integrity: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sector_size: Option<u32>,
+}
+
+impl LuksMetadata {
+    /// Where the detached header of the volume in `volume_dir` is, if it has
+    /// one.
+    pub fn header_path(&self, volume_dir: &Path) -> Option<PathBuf> {
+        self.header.as_ref().map(|header| volume_dir.join(header))
+    }
+}
+
+/// Everything worth knowing about a volume that can't be read back from its
+/// image, kept in `volume.json` beside it.
+#[derive(Serialize, Deserialize)]
+pub struct VolumeMetadata {
+    pub version: u32,
+    /// The options the volume was created with, daemon defaults included.
+    #[serde(default)]
+    pub options: HashMap<String, String>,
+    pub luks: LuksMetadata,
+    pub fs_type: String,
+    pub created_at: Option<String>,
+    pub hsm_backend: Option<String>,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    #[serde(default)]
+    pub shred: bool,
+    /// The volume this one was cloned from, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cloned_from: Option<String>,
+}
+
+impl VolumeMetadata {
+    pub fn new(
+        opts: HashMap<String, String>,
+        options: &VolumeOptions,
+        luks_uuid: String,
+        header: Option<String>,
+        created_at: String,
+        hsm_backend: &str,
+    ) -> Self {
+        let cipher = &options.luks.cipher;
+
+        let mut metadata = Self {
+            version: ATTACHED_HEADER_VERSION,
+            options: opts,
+            luks: LuksMetadata {
+                version: options.luks.version.to_string(),
+                uuid: Some(luks_uuid),
+                cipher: cipher.cipher.clone(),
+                cipher_mode: cipher.cipher_mode.clone(),
+                hash: Some(cipher.hash.clone()),
+                key_size: cipher.key_size,
+                pbkdf: Some(options.luks.pbkdf.to_string()),
+                header: None,
+                integrity: options
+                    .luks
+                    .integrity
+                    .map(|integrity| integrity.to_string()),
+                sector_size: options.luks.sector_size,
+            },
+            fs_type: options.filesystem.fs_type.to_string(),
+            created_at: Some(created_at),
+            hsm_backend: Some(hsm_backend.to_string()),
+            labels: options.labels.clone(),
+            shred: options.shred,
+            cloned_from: None,
+        };
+        metadata.set_header(header);
+
+        metadata
+    }
+
+    /// Records where the detached header is, if there is one, and the
+    /// version of the layout that takes.
+    pub fn set_header(&mut self, header: Option<String>) {
+        self.version = if header.is_some() {
+            METADATA_VERSION
+        } else {
+            ATTACHED_HEADER_VERSION
+        };
+        self.luks.header = header;
+    }
+
+    /// Reads `volume.json` from a volume directory. Volumes created before
+    /// the file existed have none, which is not an error.
+    pub fn load(volume_dir: &Path) -> Result<Option<Self>, String> {
+        let path = volume_dir.join(METADATA_FILE);
+        let contents = match fs::read(&path) {
+            Ok(contents) => contents,
+            Err(ref why) if why.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(why) => return Err(format!("Unable to read {}: {}", path.display(), why)),
+        };
+
+        let metadata: Self = serde_json::from_slice(&contents)
+            .map_err(|why| format!("Unable to parse {}: {}", path.display(), why))?;
+        if metadata.version > METADATA_VERSION {
+            return Err(format!(
+                "{} is version {}, but only versions up to {} are understood",
+                path.display(),
+                metadata.version,
+                METADATA_VERSION
+            ));
+        }
+
+        Ok(Some(metadata))
+    }
+
+    pub fn save(&self, volume_dir: &Path) -> Result<(), String> {
+        let path = volume_dir.join(METADATA_FILE);
+        let contents = serde_json::to_vec_pretty(self)
+            .map_err(|why| format!("Unable to serialise volume metadata: {}", why))?;
+
+        host::write_atomic(&path, &contents)
+            .map_err(|why| format!("Unable to write {}: {}", path.display(), why))
+    }
+
+    /// The metadata as reported in the status of the volume.
+    pub fn status(&self) -> HashMap<String, Value> {
+        let mut status = HashMap::new();
+        status.insert("luks_version".to_string(), self.luks.version.clone().into());
+        status.insert("cipher".to_string(), self.luks.cipher.clone().into());
+        status.insert(
+            "cipher_mode".to_string(),
+            self.luks.cipher_mode.clone().into(),
+        );
+        status.insert("key_size".to_string(), self.luks.key_size.into());
+        if let Some(uuid) = &self.luks.uuid {
+            status.insert("luks_uuid".to_string(), uuid.clone().into());
+        }
+        if let Some(hash) = &self.luks.hash {
+            status.insert("hash".to_string(), hash.clone().into());
+        }
+        if let Some(pbkdf) = &self.luks.pbkdf {
+            status.insert("pbkdf".to_string(), pbkdf.clone().into());
+        }
+        if let Some(sector_size) = self.luks.sector_size {
+            status.insert("sector_size".to_string(), sector_size.into());
+        }
+        if let Some(integrity) = &self.luks.integrity {
+            status.insert("integrity".to_string(), integrity.clone().into());
+        }
+        if let Some(header) = &self.luks.header {
+            status.insert("luks_header".to_string(), header.clone().into());
+        }
+        status.insert("fs".to_string(), self.fs_type.clone().into());
+        if let Some(hsm_backend) = &self.hsm_backend {
+            status.insert("hsm_backend".to_string(), hsm_backend.clone().into());
+        }
+        if let Some(cloned_from) = &self.cloned_from {
+            status.insert("cloned_from".to_string(), cloned_from.clone().into());
+        }
+        if !self.labels.is_empty() {
+            status.insert(
+                "labels".to_string(),
+                serde_json::to_value(&self.labels).unwrap_or_default(),
+            );
+        }
+
+        status
+    }
+}
+
+#[test]
+fn test_metadata_round_trip() {
+    let mut opts = HashMap::new();
+    opts.insert("fs".to_string(), "xfs".to_string());
+    opts.insert("label.team".to_string(), "storage".to_string());
+    let options = VolumeOptions::from_opts(opts.clone()).unwrap();
+    let metadata = VolumeMetadata::new(
+        opts,
+        &options,
+        "3f5e7d3c-1d2b-4d8e-9a51-9f1c2d3e4f50".to_string(),
+        None,
+        "2019-12-01T00:00:00+00:00".to_string(),
+        "dummy",
+    );
+
+    let json = serde_json::to_string(&metadata).unwrap();
+    let parsed: VolumeMetadata = serde_json::from_str(&json).unwrap();
+    assert_eq!(parsed.version, ATTACHED_HEADER_VERSION);
+    assert_eq!(parsed.luks.header_path(Path::new("/data/vol")), None);
+    assert_eq!(parsed.fs_type, "xfs");
+    assert_eq!(parsed.luks.version, "luks2");
+    assert_eq!(
+        parsed.labels.get("team").map(String::as_str),
+        Some("storage")
+    );
+    assert!(VolumeOptions::from_opts(parsed.options).is_ok());
+}
+
+#[test]
+fn test_metadata_detached_header() {
+    let options = VolumeOptions::from_opts(HashMap::new()).unwrap();
+    let metadata = VolumeMetadata::new(
+        HashMap::new(),
+        &options,
+        "3f5e7d3c-1d2b-4d8e-9a51-9f1c2d3e4f50".to_string(),
+        Some(HEADER_FILE.to_string()),
+        "2019-12-01T00:00:00+00:00".to_string(),
+        "dummy",
+    );
+    assert_eq!(metadata.version, METADATA_VERSION);
+    assert_eq!(
+        metadata.luks.header_path(Path::new("/data/vol")),
+        Some(PathBuf::from("/data/vol/header.img"))
+    );
+
+    let mut metadata = metadata;
+    metadata.luks.header = Some("/headers/vol.header".to_string());
+    assert_eq!(
+        metadata.luks.header_path(Path::new("/data/vol")),
+        Some(PathBuf::from("/headers/vol.header"))
+    );
+}
@@ -0,0 +1,1986 @@
+mod busy;
+mod cipher;
+mod erase;
+mod filesystem;
+mod host;
+mod metadata;
+mod mount;
+mod options;
+mod params;
+mod rollback;
+mod snapshot;
+mod state;
+
+use crate::crypto::{self, DummyHSM, VirtualHSM};
+use crate::plugin::admin::{AdminDriver, Snapshot};
+use crate::plugin::error::{DriverError, DriverResult};
+use crate::plugin::name::validate_volume_name;
+use crate::plugin::{volume, VolumeDriver};
+
+use cryptsetup_rs::api::{CryptDevice, EitherLuksDevice, Luks1CryptDevice, LuksCryptDevice};
+use cryptsetup_rs::{crypt_rng_type, format, open};
+
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+use busy::BusyVolumes;
+use filesystem::{FilesystemOptions, FilesystemType};
+use metadata::{LuksMetadata, VolumeMetadata, HEADER_FILE, METADATA_VERSION};
+use mount::MountOptions;
+use options::VolumeOptions;
+use params::{ActivationFlags, Discard, LuksOptions, LuksVersion};
+use rollback::Rollback;
+use state::{DriverState, MountState, StateStore};
+
+const MAPPER_PREFIX: &str = "luks-";
+/// Mappings opened while a volume is built or resized. Volume names start
+/// with a letter or digit, so no volume's own mapper name has this prefix.
+const TEMP_MAPPER_PREFIX: &str = "luks_tmp-";
+const STATE_FILE: &str = ".state.json";
+/// New volumes are built under a hidden name and renamed into place once
+/// complete, so a half-made volume is never visible.
+const STAGING_SUFFIX: &str = ".creating";
+/// Detached headers in the header dir are named after their volume.
+const HEADER_SUFFIX: &str = ".header";
+/// Records, in a staging dir, the detached header that the volume being built
+/// has in the header dir, so that it can be erased if the build never
+/// finishes.
+const STAGED_HEADER_FILE: &str = "staged-header";
+
+pub type DriverHSM = dyn VirtualHSM + Send + Sync;
+
+/// Sparse images only take space as they are written, so one that has been
+/// allowed to overcommit is only warned about.
+fn check_space(
+    name: &str,
+    size: u64,
+    available: u64,
+    overcommit: bool,
+    data_dir: &Path,
+) -> DriverResult<()> {
+    if size <= available {
+        return Ok(());
+    }
+
+    let why = format!(
+        "volume {} needs {} bytes but only {} are free in {}",
+        name,
+        size,
+        available,
+        data_dir.display()
+    );
+    if !overcommit {
+        return Err(DriverError::NoSpace(why));
+    }
+    warn!("Overcommitting sparse volume: {}", why);
+
+    Ok(())
+}
+
+/// The half-built volumes or snapshots in `dir`.
+fn staging_dirs(dir: &Path) -> Vec<PathBuf> {
+    fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .map_or(false, |name| {
+                    name.starts_with('.') && name.ends_with(STAGING_SUFFIX)
+                })
+        })
+        .collect()
+}
+
+/// Notes in `staging_dir` that `header`, outside of it, belongs to the volume
+/// being built there. Headers inside the staging dir go with it anyway.
+fn mark_staged_header(staging_dir: &Path, header: &Path) -> DriverResult<()> {
+    if header.starts_with(staging_dir) {
+        return Ok(());
+    }
+
+    host::write_atomic(
+        &staging_dir.join(STAGED_HEADER_FILE),
+        header.to_string_lossy().as_bytes(),
+    )
+    .map_err(|why| DriverError::io(format!("Unable to record header {}", header.display()), why))
+}
+
+/// Erases the detached header that an unfinished volume in `staging_dir`
+/// recorded as its own, if it got as far as creating one.
+fn erase_staged_header(staging_dir: &Path) -> Result<(), String> {
+    let header = match fs::read_to_string(staging_dir.join(STAGED_HEADER_FILE)) {
+        Ok(header) => PathBuf::from(header),
+        Err(ref why) if why.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(why) => return Err(why.to_string()),
+    };
+    if !header.exists() {
+        return Ok(());
+    }
+
+    warn!(
+        "Erasing the LUKS header {} of an incomplete volume",
+        header.display()
+    );
+    erase::erase_header(&header).and_then(|_| erase::shred_and_remove(&header))
+}
+
+/// Creates the staging dir a new volume is built in, and records how to
+/// remove it again along with the volume's detached header, if it has one.
+/// The header is removed on rollback whether or not it was ever written,
+/// since cryptsetup may create it before failing.
+fn create_staging_dir<'a>(
+    staging_dir: &'a Path,
+    header: Option<&'a Path>,
+    rollback: &mut Rollback<'a>,
+) -> DriverResult<()> {
+    fs::create_dir(staging_dir).map_err(|why| {
+        let message = format!(
+            "Unable to create the volume directory {}: {}",
+            staging_dir.display(),
+            why
+        );
+        match why.kind() {
+            io::ErrorKind::AlreadyExists => DriverError::Busy(message),
+            _ => DriverError::Io(message),
+        }
+    })?;
+    rollback.push("create the volume directory", move || {
+        fs::remove_dir_all(staging_dir).map_err(|why| DriverError::Io(why.to_string()))
+    });
+
+    if let Some(header) = header {
+        mark_staged_header(staging_dir, header)?;
+        rollback.push(
+            "create the detached LUKS header",
+            move || match fs::remove_file(header) {
+                Err(ref why) if why.kind() == io::ErrorKind::NotFound => Ok(()),
+                removed => removed.map_err(|why| DriverError::Io(why.to_string())),
+            },
+        );
+    }
+
+    Ok(())
+}
+
+pub struct LuksVolumeDriver {
+    pub data_dir: PathBuf,
+    pub mount_dir: PathBuf,
+    hsm: Box<DriverHSM>,
+    default_opts: HashMap<String, String>,
+    /// Mount options that every volume is mounted with, whatever it asks for.
+    mandatory_mount_opts: MountOptions,
+    /// Where detached LUKS headers are kept, if not beside their image.
+    header_dir: Option<PathBuf>,
+    /// The volumes currently mounted, keyed by volume name. Mounting,
+    /// unmounting, resizing and removing a volume hold this throughout, and
+    /// refuse a volume that is claimed in `busy`.
+    state: Mutex<DriverState>,
+    /// The volumes that a longer operation is working on: creating, cloning,
+    /// and taking or restoring snapshots claim their volumes here and only
+    /// take the state lock briefly, if at all. `trim_volumes` takes neither,
+    /// and works from a copy of the mounts.
+    busy: BusyVolumes,
+    store: StateStore,
+}
+
+impl LuksVolumeDriver {
+    pub fn new(data_dir: &str, mount_dir: &str, hsm: Option<Box<DriverHSM>>) -> Self {
+        let data_dir = Path::new(data_dir)
+            .canonicalize()
+            .expect("Not a valid path for data_dir");
+        let store = StateStore::new(&data_dir.join(STATE_FILE));
+        let state = store.load().unwrap_or_else(|why| {
+            error!("Discarding unreadable driver state: {}", why);
+            DriverState::default()
+        });
+
+        let driver = Self {
+            data_dir,
+            mount_dir: Path::new(mount_dir)
+                .canonicalize()
+                .expect("Not a valid path for data_dir"),
+            hsm: match hsm {
+                Some(hsm) => hsm,
+                None => Box::new(DummyHSM::new()),
+            },
+            default_opts: HashMap::new(),
+            mandatory_mount_opts: MountOptions::default(),
+            header_dir: None,
+            state: Mutex::new(state),
+            busy: BusyVolumes::default(),
+            store,
+        };
+        driver.reconcile();
+
+        driver
+    }
+
+    /// Sets daemon-wide volume options, which apply to every new volume unless
+    /// they are overridden by the options given to `create`.
+    pub fn with_default_opts(mut self, opts: HashMap<String, String>) -> DriverResult<Self> {
+        VolumeOptions::from_opts(opts.clone()).map_err(|why| {
+            DriverError::InvalidOptions(format!("Invalid default volume options: {}", why))
+        })?;
+        self.default_opts = opts;
+
+        Ok(self)
+    }
+
+    /// Sets mount options, such as `nodev,nosuid`, that every volume is
+    /// mounted with regardless of its own options.
+    pub fn with_mandatory_mount_opts(mut self, opts: &str) -> DriverResult<Self> {
+        self.mandatory_mount_opts = MountOptions::parse(opts).map_err(|why| {
+            DriverError::InvalidOptions(format!("Invalid mandatory mount options: {}", why))
+        })?;
+
+        Ok(self)
+    }
+
+    /// Keeps the detached headers of new volumes in `dir` rather than in
+    /// their volume directories, so that they can live on other storage.
+    pub fn with_header_dir(mut self, dir: &str) -> DriverResult<Self> {
+        fs::create_dir_all(dir)
+            .and_then(|_| fs::set_permissions(dir, fs::Permissions::from_mode(0o700)))
+            .map_err(|why| DriverError::io(format!("Unable to create header dir {}", dir), why))?;
+        self.header_dir = Some(Path::new(dir).canonicalize().map_err(|why| {
+            DriverError::io(format!("Not a valid path for header dir {}", dir), why)
+        })?);
+
+        Ok(self)
+    }
+
+    /// The device-mapper name used while a volume is mounted. It is derived
+    /// from the volume rather than the mount ID so that it is shared by every
+    /// container using the volume.
+    fn mapper_name(name: &str) -> DriverResult<String> {
+        let mapper_name = format!("{}{}", MAPPER_PREFIX, name);
+        host::validate_mapper_name(&mapper_name)?;
+
+        Ok(mapper_name)
+    }
+
+    /// The device-mapper name used while a volume is being built or resized
+    /// without being mounted. It is recognisable so that `reconcile` can
+    /// close it if the driver dies before it does.
+    fn temp_mapper_name(name: &str) -> DriverResult<String> {
+        let mapper_name = format!("{}{}", TEMP_MAPPER_PREFIX, name);
+        host::validate_mapper_name(&mapper_name)?;
+
+        Ok(mapper_name)
+    }
+
+    fn save_state(&self, state: &DriverState) {
+        if let Err(why) = self.store.save(state) {
+            error!("Unable to persist driver state: {}", why);
+        }
+    }
+
+    /// Brings the persisted state back in line with the host after a restart.
+    /// Mounts that are still live are adopted again, while entries whose mount
+    /// or mapping has gone are torn down and forgotten. Mappings of our own
+    /// volumes that nothing has mounted are closed too, as are temporary
+    /// mappings an interrupted operation left open. Volumes left
+    /// half-built by an interrupted `create` are removed, along with their
+    /// detached headers, and interrupted snapshot restores are finished or
+    /// undone.
+    fn reconcile(&self) {
+        let (mounted, mappers) = match (host::mounted_paths(), host::active_mappers()) {
+            (Ok(mounted), Ok(mappers)) => (mounted, mappers),
+            (Err(why), _) | (_, Err(why)) => {
+                error!("Unable to reconcile driver state: {}", why);
+                return;
+            }
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.volumes.retain(|name, mount| {
+            let is_mounted = mounted.contains(Path::new(&mount.mountpoint));
+            let is_mapped = mappers.contains(&mount.mapper_name);
+            if is_mounted && is_mapped {
+                info!(
+                    "Adopting volume {} mounted at {} for {} container(s)",
+                    name,
+                    mount.mountpoint,
+                    mount.ids.len()
+                );
+                return true;
+            }
+
+            warn!("Cleaning up stale mount of volume {}", name);
+            if is_mounted {
+                if let Err(why) =
+                    sys_mount::unmount(&mount.mountpoint, sys_mount::UnmountFlags::DETACH)
+                {
+                    warn!("Unable to unmount {}: {}", mount.mountpoint, why);
+                }
+            }
+            if is_mapped {
+                let volume_img = self.data_dir.join(name).join("volume.img");
+                let header = self.luks_header(name).unwrap_or_default();
+                if let Err(why) =
+                    self.deactivate_luks_device(&mount.mapper_name, &volume_img, header.as_deref())
+                {
+                    warn!("Unable to close mapping {}: {}", mount.mapper_name, why);
+                }
+            }
+            let _ = fs::remove_dir(&mount.mountpoint);
+
+            false
+        });
+
+        for mapper_name in mappers.iter().filter(|m| m.starts_with(MAPPER_PREFIX)) {
+            let name = &mapper_name[MAPPER_PREFIX.len()..];
+            let volume_img = self.data_dir.join(name).join("volume.img");
+            if state.volumes.contains_key(name)
+                || !volume_img.exists()
+                || mounted.contains(&self.mount_dir.join(name))
+            {
+                continue;
+            }
+
+            warn!(
+                "Closing orphaned mapping {} of volume {}",
+                mapper_name, name
+            );
+            let header = self.luks_header(name).unwrap_or_default();
+            if let Err(why) =
+                self.deactivate_luks_device(&mapper_name, &volume_img, header.as_deref())
+            {
+                warn!("Unable to close mapping {}: {}", mapper_name, why);
+            }
+        }
+
+        for mapper_name in mappers.iter().filter(|m| m.starts_with(TEMP_MAPPER_PREFIX)) {
+            let name = &mapper_name[TEMP_MAPPER_PREFIX.len()..];
+            warn!(
+                "Closing temporary mapping {} left by an interrupted operation on volume {}",
+                mapper_name, name
+            );
+            let mount_dirs = [
+                self.mount_dir.join(format!(".{}{}", name, STAGING_SUFFIX)),
+                self.mount_dir.join(name),
+            ];
+            let is_stale =
+                |dir: &&PathBuf| mounted.contains(*dir) && !state.volumes.contains_key(name);
+            for mount_dir in mount_dirs.iter().filter(is_stale) {
+                if let Err(why) = sys_mount::unmount(mount_dir, sys_mount::UnmountFlags::DETACH) {
+                    warn!("Unable to unmount {}: {}", mount_dir.display(), why);
+                }
+            }
+            if let Err(why) = host::run(
+                Command::new("cryptsetup").arg("close").arg(mapper_name),
+                None,
+            ) {
+                warn!("Unable to close mapping {}: {}", mapper_name, why);
+            }
+        }
+
+        let volume_names = fs::read_dir(&self.data_dir)
+            .into_iter()
+            .flatten()
+            .filter_map(Result::ok)
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .filter(|name| validate_volume_name(name).is_ok());
+        for name in volume_names {
+            let header = self.luks_header(&name).unwrap_or_default();
+            if let Err(why) =
+                snapshot::recover_restore(&self.data_dir.join(&name), header.as_deref())
+            {
+                warn!("Unable to recover volume {}: {}", name, why);
+            }
+        }
+
+        for staging_dir in staging_dirs(&self.data_dir) {
+            warn!("Removing incomplete volume {}", staging_dir.display());
+            if let Err(why) = erase_staged_header(&staging_dir) {
+                warn!(
+                    "Unable to erase the header of {}: {}",
+                    staging_dir.display(),
+                    why
+                );
+            }
+            if let Err(why) = fs::remove_dir_all(&staging_dir) {
+                warn!("Unable to remove {}: {}", staging_dir.display(), why);
+            }
+        }
+        let snapshot_dirs = fs::read_dir(&self.data_dir)
+            .into_iter()
+            .flatten()
+            .filter_map(Result::ok)
+            .map(|entry| entry.path().join(snapshot::SNAPSHOT_DIR));
+        for staging_dir in snapshot_dirs.flat_map(|dir| staging_dirs(&dir)) {
+            warn!("Removing incomplete snapshot {}", staging_dir.display());
+            if let Err(why) = snapshot::erase_snapshot(&staging_dir, false) {
+                warn!("Unable to remove {}: {}", staging_dir.display(), why);
+            }
+        }
+
+        self.save_state(&state);
+    }
+
+    fn get_luks_key(&self, name: &str) -> DriverResult<Vec<u8>> {
+        self.read_luks_key(&self.data_dir.join(&name))
+    }
+
+    /// Reads and unwraps the keyfile in `dir`, which is a volume directory or
+    /// a snapshot.
+    fn read_luks_key(&self, dir: &Path) -> DriverResult<Vec<u8>> {
+        let key_file = &dir.join("keyfile");
+        fs::metadata(&key_file).map(|_| &key_file).map_err(|why| {
+            DriverError::NotFound(format!(
+                "Unable to get key for {}: {:?}",
+                &dir.display(),
+                why
+            ))
+        })?;
+
+        let key_data = fs::read(&key_file).map_err(|why| {
+            DriverError::Io(format!(
+                "Unable to read key file {}: {:?}",
+                &key_file.display(),
+                why
+            ))
+        })?;
+        self.hsm.decrypt(key_data).map_err(|e| {
+            DriverError::from(e).context(format!(
+                "Unable to decrypt key file {}",
+                &key_file.display()
+            ))
+        })
+    }
+
+    fn store_luks_key(&self, volume_dir: &Path, key_data: &[u8]) -> DriverResult<()> {
+        let key_file = &volume_dir.join("keyfile");
+
+        let encrypted_blob = self.hsm.encrypt(key_data.to_vec()).map_err(|e| {
+            DriverError::from(e).context(format!("Unable to encrypt key {}", &key_file.display()))
+        })?;
+
+        host::write_atomic(&key_file, &encrypted_blob).map_err(|why| {
+            DriverError::Io(format!(
+                "Unable to wite key file {}: {:?}",
+                &key_file.display(),
+                why
+            ))
+        })?;
+
+        Ok(())
+    }
+
+    fn set_image_len(&self, image: &Path, size: u64) -> DriverResult<()> {
+        fs::OpenOptions::new()
+            .write(true)
+            .open(image)
+            .and_then(|file| {
+                file.set_len(size)?;
+                file.sync_all()
+            })
+            .map_err(|why| {
+                DriverError::Io(format!("Unable to resize {}: {}", image.display(), why))
+            })
+    }
+
+    /// Refuses to give a volume `size` more bytes than the data dir has room
+    /// for, unless it was created with `overcommit=true`.
+    fn check_free_space(&self, name: &str, size: u64, overcommit: bool) -> DriverResult<()> {
+        let available = host::available_bytes(&self.data_dir).map_err(DriverError::Io)?;
+
+        check_space(name, size, available, overcommit, &self.data_dir)
+    }
+
+    /// Reserves disk space for the whole of an image, growing it to `size`
+    /// if it is smaller.
+    fn preallocate_image(&self, image: &Path, size: u64) -> DriverResult<()> {
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .open(image)
+            .map_err(|why| DriverError::io(format!("Unable to open {}", image.display()), why))?;
+
+        host::fallocate(&file, size)
+            .and_then(|_| file.sync_all())
+            .map_err(|why| {
+                DriverError::io(format!("Unable to preallocate {}", image.display()), why)
+            })
+    }
+
+    /// Creates the image file of a new volume, readable only by root. It is
+    /// sparse unless `preallocate` is set, in which case all of its space is
+    /// reserved up front.
+    fn create_disk_image(&self, location: &Path, size: u64, preallocate: bool) -> DriverResult<()> {
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(location)
+            .map_err(|why| DriverError::io("Unable to create the disk image", why))?;
+
+        if preallocate {
+            host::fallocate(&file, size)
+        } else {
+            file.set_len(size)
+        }
+        .and_then(|_| file.sync_all())
+        .map_err(|why| DriverError::io("Unable to size the disk image", why))?;
+
+        match location.parent() {
+            Some(dir) => host::sync_dir(dir)
+                .map_err(|why| DriverError::io(format!("Unable to sync {}", dir.display()), why)),
+            None => Ok(()),
+        }
+    }
+    /// Formats a LUKS image, with its header either at the start of the image
+    /// or, if `header` is given, in a file of its own. The bindings can't
+    /// format detached headers, set up dm-integrity or change the sector size,
+    /// so those go through `cryptsetup`. It also wipes an image with
+    /// dm-integrity so that every sector starts out with a valid tag.
+    fn format_luks_device(
+        &self,
+        image: &Path,
+        header: Option<&Path>,
+        key: &[u8],
+        uuid: &Uuid,
+        options: &LuksOptions,
+    ) -> DriverResult<()> {
+        if header.is_some() || options.needs_cryptsetup() {
+            let mut command = Command::new("cryptsetup");
+            command
+                .arg("luksFormat")
+                .arg("--batch-mode")
+                .arg("--key-file=-")
+                .arg(format!("--uuid={}", uuid));
+            if let Some(header) = header {
+                command.arg("--header").arg(header);
+            }
+            return host::run(
+                command.args(options.cryptsetup_format_args()).arg(image),
+                Some(key),
+            )
+            .map(|_| ())
+            .map_err(|why| {
+                DriverError::Crypto(format!("Unable to format the LUKS device: {}", why))
+            });
+        }
+
+        let cipher = &options.cipher;
+        let do_steps = || -> Result<(), String> {
+            let builder = format(&image)
+                .map_err(|_| "Unable to create a builder to format the LUKS image".to_string())?
+                .rng_type(crypt_rng_type::CRYPT_RNG_URANDOM)
+                .iteration_time(options.iteration_time);
+
+            match options.version {
+                LuksVersion::Luks1 => {
+                    let mut device_handle = builder
+                        .luks1(
+                            &cipher.cipher,
+                            &cipher.cipher_mode,
+                            &cipher.hash,
+                            cipher.key_size,
+                            Some(uuid),
+                        )
+                        .map_err(|_| "Unable to format the LUKS1 image".to_string())?;
+                    device_handle
+                        .add_keyslot(&key, None, None)
+                        .map_err(|_| "Unable to add key to LUKS keyslot".to_string())?;
+                }
+                LuksVersion::Luks2 => {
+                    let mut luks2 = builder
+                        .luks2(
+                            &cipher.cipher,
+                            &cipher.cipher_mode,
+                            cipher.key_size,
+                            Some(uuid),
+                        )
+                        .hash(&cipher.hash)
+                        .pbkdf_type(options.pbkdf.algo_type());
+                    if let Some(memory) = options.pbkdf_memory {
+                        luks2 = luks2.pbkdf_max_memory(memory);
+                    }
+                    if let Some(iterations) = options.pbkdf_iterations {
+                        luks2 = luks2.pbkdf_iterations(iterations);
+                    }
+                    let mut device_handle = luks2
+                        .start()
+                        .map_err(|_| "Unable to format the LUKS2 image".to_string())?;
+                    device_handle
+                        .add_keyslot(&key, None, None)
+                        .map_err(|_| "Unable to add key to LUKS keyslot".to_string())?;
+                }
+            }
+
+            Ok(())
+        };
+
+        do_steps().map_err(|why| {
+            DriverError::Crypto(format!("Unable to format the LUKS device: {}", why))
+        })
+    }
+    /// Opens the LUKS image, detecting whether it carries a LUKS1 or a LUKS2
+    /// header so that volumes created before LUKS2 support keep working.
+    fn open_luks_device(&self, image: &Path) -> DriverResult<EitherLuksDevice> {
+        let image = String::from(image.to_str().unwrap_or_default());
+
+        open(&image)
+            .map_err(|why| {
+                DriverError::Crypto(format!("Unable to open LUKS image {}: {:?}", &image, why))
+            })?
+            .luks()
+            .map_err(|why| {
+                DriverError::Crypto(format!(
+                    "Unable to get device handle for LUKS image {}: {:?}",
+                    &image, why
+                ))
+            })
+    }
+    /// Reads the encryption parameters back out of the LUKS header, for
+    /// volumes that predate `volume.json`.
+    fn read_luks_header(&self, image: &Path) -> DriverResult<LuksMetadata> {
+        let (version, cipher, cipher_mode, key_bytes, hash) = match self.open_luks_device(&image)? {
+            EitherLuksDevice::Luks1(device) => (
+                LuksVersion::Luks1,
+                device.cipher().map(String::from),
+                device.cipher_mode().map(String::from),
+                device.volume_key_size(),
+                device.hash_spec().map(String::from),
+            ),
+            EitherLuksDevice::Luks2(device) => (
+                LuksVersion::Luks2,
+                device.cipher().map(String::from),
+                device.cipher_mode().map(String::from),
+                device.volume_key_size(),
+                None,
+            ),
+        };
+
+        Ok(LuksMetadata {
+            version: version.to_string(),
+            uuid: None,
+            cipher: cipher.unwrap_or_default(),
+            cipher_mode: cipher_mode.unwrap_or_default(),
+            hash,
+            key_size: key_bytes.map_or(0, |bytes| usize::from(bytes) * 8),
+            pbkdf: None,
+            header: None,
+            integrity: None,
+            sector_size: None,
+        })
+    }
+    /// Loads the volume's `volume.json`. Volumes created before it existed
+    /// get what it would have said: the LUKS parameters are read from the
+    /// header and everything else takes the defaults of the time.
+    fn volume_metadata(&self, name: &str) -> DriverResult<VolumeMetadata> {
+        let volume_dir = self.data_dir.join(name);
+        if let Some(metadata) = VolumeMetadata::load(&volume_dir).map_err(DriverError::Io)? {
+            return Ok(metadata);
+        }
+
+        let luks = self.read_luks_header(&volume_dir.join("volume.img"))?;
+        let created_at = fs::metadata(&volume_dir)
+            .and_then(|metadata| metadata.created().or_else(|_| metadata.modified()))
+            .ok()
+            .map(|time| DateTime::<Utc>::from(time).to_rfc3339());
+
+        Ok(VolumeMetadata {
+            version: METADATA_VERSION,
+            options: HashMap::new(),
+            luks,
+            fs_type: FilesystemType::Ext4.to_string(),
+            created_at,
+            hsm_backend: Some(self.hsm.backend().to_string()),
+            labels: HashMap::new(),
+            shred: false,
+            cloned_from: None,
+        })
+    }
+    /// The detached header of a volume, if it has one. Only volumes with a
+    /// `volume.json` can have one, so the image is never consulted.
+    fn luks_header(&self, name: &str) -> DriverResult<Option<PathBuf>> {
+        let volume_dir = self.data_dir.join(name);
+
+        Ok(VolumeMetadata::load(&volume_dir)
+            .map_err(DriverError::Io)?
+            .and_then(|metadata| metadata.luks.header_path(&volume_dir)))
+    }
+
+    /// Looks for a volume's detached header where this driver would have put
+    /// it, for when `volume.json` can't be read: beside the image, or in the
+    /// header dir.
+    fn probe_luks_header(&self, name: &str) -> Option<PathBuf> {
+        let beside_image = self.data_dir.join(name).join(HEADER_FILE);
+        let in_header_dir = self
+            .header_dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{}{}", name, HEADER_SUFFIX)));
+
+        std::iter::once(beside_image)
+            .chain(in_header_dir)
+            .find(|header| header.exists())
+    }
+
+    /// Describes a volume for `get` and `list`: where it is mounted, when it
+    /// was created, and a status combining its metadata, the size of the
+    /// image and the containers using it.
+    fn describe_volume(&self, name: &str, state: &DriverState) -> DriverResult<volume::Volume> {
+        let volume_dir = self.data_dir.join(name);
+        let volume_img = volume_dir.join("volume.img");
+        let metadata = fs::metadata(&volume_img).map_err(|why| {
+            DriverError::NotFound(format!("Unable to find volume image: {}", why))
+        })?;
+
+        let volume_metadata = match self.volume_metadata(name) {
+            Ok(volume_metadata) => Some(volume_metadata),
+            Err(why) => {
+                warn!("Unable to read the metadata of volume {}: {}", name, why);
+                None
+            }
+        };
+        let mut status = volume_metadata
+            .as_ref()
+            .map(VolumeMetadata::status)
+            .unwrap_or_default();
+        status.insert("size".to_string(), metadata.len().into());
+        status.insert("allocated".to_string(), (metadata.blocks() * 512).into());
+
+        let mount = state.volumes.get(name);
+        let mut mount_ids: Vec<String> = mount
+            .map(|mount| mount.ids.iter().cloned().collect())
+            .unwrap_or_default();
+        mount_ids.sort();
+        status.insert("mount_ids".to_string(), mount_ids.into());
+        if let Some(mount) = mount {
+            match host::used_bytes(Path::new(&mount.mountpoint)) {
+                Ok(used) => {
+                    status.insert("used".to_string(), used.into());
+                }
+                Err(why) => warn!("Unable to get the usage of volume {}: {}", name, why),
+            }
+            let integrity = volume_metadata.as_ref().map_or(false, |volume_metadata| {
+                volume_metadata.luks.integrity.is_some()
+            });
+            if integrity {
+                match host::integrity_mismatches(&mount.mapper_name) {
+                    Ok(mismatches) => {
+                        status.insert("integrity_mismatches".to_string(), mismatches.into());
+                    }
+                    Err(why) => warn!(
+                        "Unable to get the integrity status of volume {}: {}",
+                        name, why
+                    ),
+                }
+            }
+        }
+
+        Ok(volume::Volume {
+            name: name.to_string(),
+            mountpoint: mount.map(|mount| mount.mountpoint.clone()),
+            created_at: volume_metadata.and_then(|volume_metadata| volume_metadata.created_at),
+            status: Some(status),
+        })
+    }
+    /// Opens the dm-crypt mapping of a LUKS image. Mappings that need flags
+    /// the bindings can't set, or whose header is detached, are opened
+    /// through `cryptsetup` instead.
+    fn activate_luks_device(
+        &self,
+        name: &str,
+        image: &Path,
+        header: Option<&Path>,
+        key: &[u8],
+        flags: ActivationFlags,
+    ) -> DriverResult<PathBuf> {
+        if flags == ActivationFlags::default() && header.is_none() {
+            let activated = match self.open_luks_device(&image)? {
+                EitherLuksDevice::Luks1(mut device) => device.activate(&name, &key).map(|_| ()),
+                EitherLuksDevice::Luks2(mut device) => device.activate(&name, &key).map(|_| ()),
+            };
+            activated.map_err(|_| {
+                DriverError::DeviceMapper(format!("Unable to activate LUKS device {}", name))
+            })?;
+        } else {
+            let mut command = Command::new("cryptsetup");
+            command
+                .arg("open")
+                .arg("--type=luks")
+                .arg("--key-file=-")
+                .args(flags.cryptsetup_args());
+            if let Some(header) = header {
+                command.arg("--header").arg(header);
+            }
+            host::run(command.arg(image).arg(name), Some(key)).map_err(|why| {
+                DriverError::DeviceMapper(format!(
+                    "Unable to activate LUKS device {}: {}",
+                    name, why
+                ))
+            })?;
+        }
+
+        Ok(PathBuf::from(format!("/dev/mapper/{}", &name)))
+    }
+    /// Makes a mapping pick up a change in the size of its image, along with
+    /// the loop device underneath it.
+    fn refresh_luks_device(
+        &self,
+        name: &str,
+        image: &Path,
+        header: Option<&Path>,
+        key: &[u8],
+    ) -> DriverResult<()> {
+        let loop_device = host::loop_device(image)
+            .map_err(DriverError::DeviceMapper)?
+            .ok_or_else(|| {
+                DriverError::DeviceMapper(format!("{} is not attached to a loop device", name))
+            })?;
+        host::refresh_loop_device(&loop_device).map_err(DriverError::DeviceMapper)?;
+
+        let mut command = Command::new("cryptsetup");
+        command.arg("resize").arg("--key-file=-");
+        if let Some(header) = header {
+            command.arg("--header").arg(header);
+        }
+        host::run(command.arg(name), Some(key))
+            .map(|_| ())
+            .map_err(|why| {
+                DriverError::DeviceMapper(format!("Unable to resize LUKS device {}: {}", name, why))
+            })
+    }
+    /// Re-encrypts a LUKS image under a new volume key, unlocking it with
+    /// `key`. The passphrases of its keyslots stay as they were.
+    fn reencrypt_luks_device(
+        &self,
+        image: &Path,
+        header: Option<&Path>,
+        key: &[u8],
+    ) -> DriverResult<()> {
+        let mut command = Command::new("cryptsetup");
+        command
+            .arg("reencrypt")
+            .arg("--batch-mode")
+            .arg("--key-file=-");
+        if let Some(header) = header {
+            command.arg("--header").arg(header);
+        }
+        host::run(command.arg(image), Some(key))
+            .map(|_| ())
+            .map_err(|why| {
+                DriverError::Crypto(format!("Unable to re-encrypt {}: {}", image.display(), why))
+            })
+    }
+    /// Replaces the passphrase `key` of a LUKS header with `new_key`. A
+    /// detached header is a LUKS device in its own right for this.
+    fn change_luks_key(&self, header: &Path, key: &[u8], new_key: &[u8]) -> DriverResult<()> {
+        let changed = match self.open_luks_device(header)? {
+            EitherLuksDevice::Luks1(mut device) => {
+                device.update_keyslot(new_key, key, None).map(|_| ())
+            }
+            EitherLuksDevice::Luks2(mut device) => {
+                device.update_keyslot(new_key, key, None).map(|_| ())
+            }
+        };
+
+        changed.map_err(|_| {
+            DriverError::Crypto(format!(
+                "Unable to change the LUKS key of {}",
+                header.display()
+            ))
+        })
+    }
+    /// Gives a LUKS header a new UUID.
+    fn set_luks_uuid(&self, header: &Path, uuid: &Uuid) -> DriverResult<()> {
+        host::run(
+            Command::new("cryptsetup")
+                .arg("luksUUID")
+                .arg("--batch-mode")
+                .arg(format!("--uuid={}", uuid))
+                .arg(header),
+            None,
+        )
+        .map(|_| ())
+        .map_err(|why| {
+            DriverError::Crypto(format!(
+                "Unable to set the LUKS UUID of {}: {}",
+                header.display(),
+                why
+            ))
+        })
+    }
+    /// Whether a mapping with dm-integrity beneath it failed because data
+    /// didn't match its tags, rather than for some other reason.
+    fn integrity_failed(&self, mapper_name: &str, why: &io::Error) -> bool {
+        if why.raw_os_error() == Some(libc::EILSEQ) {
+            return true;
+        }
+
+        match host::integrity_mismatches(mapper_name) {
+            Ok(mismatches) => mismatches > 0,
+            Err(why) => {
+                warn!(
+                    "Unable to get the integrity status of {}: {}",
+                    mapper_name, why
+                );
+                false
+            }
+        }
+    }
+    /// Closes the dm-crypt mapping of a LUKS image. The bindings can only
+    /// get at a mapping through the header, so mappings of detached headers
+    /// are closed by name with `cryptsetup`.
+    fn deactivate_luks_device(
+        &self,
+        name: &str,
+        image: &Path,
+        header: Option<&Path>,
+    ) -> DriverResult<()> {
+        if header.is_some() {
+            return host::run(Command::new("cryptsetup").arg("close").arg(name), None)
+                .map(|_| ())
+                .map_err(|why| {
+                    DriverError::DeviceMapper(format!(
+                        "Unable to deactivate LUKS device {}: {}",
+                        name, why
+                    ))
+                });
+        }
+
+        let deactivated = match self.open_luks_device(&image)? {
+            EitherLuksDevice::Luks1(device) => device.deactivate(name),
+            EitherLuksDevice::Luks2(device) => device.deactivate(name),
+        };
+
+        deactivated.map_err(|_| {
+            DriverError::DeviceMapper(format!("Unable to deactivate LUKS device {}", name))
+        })
+    }
+}
+
+impl LuksVolumeDriver {
+    /// Mounts a freshly made filesystem on a private mount dir so that the
+    /// ownership and permissions of its root can be set.
+    fn set_up_filesystem_root(
+        &self,
+        name: &str,
+        device: &Path,
+        options: &FilesystemOptions,
+    ) -> DriverResult<()> {
+        let root = self.mount_dir.join(format!(".{}{}", name, STAGING_SUFFIX));
+        fs::create_dir_all(&root).map_err(|why| {
+            DriverError::Io(format!(
+                "Unable to create mount dir {}: {}",
+                root.display(),
+                why
+            ))
+        })?;
+
+        let set_up = sys_mount::Mount::new(
+            device,
+            &root,
+            options.fs_type.to_string().as_str(),
+            sys_mount::MountFlags::empty(),
+            None,
+        )
+        .map_err(|why| {
+            DriverError::Mount(format!(
+                "Unable to mount {} on {}: {}",
+                device.display(),
+                root.display(),
+                why
+            ))
+        })
+        .and_then(|_| {
+            let set_up = options.set_up_root(&root).map_err(DriverError::Filesystem);
+            let unmounted =
+                sys_mount::unmount(&root, sys_mount::UnmountFlags::empty()).map_err(|why| {
+                    DriverError::Mount(format!("Unable to unmount {}: {}", root.display(), why))
+                });
+            set_up.and(unmounted)
+        });
+        let _ = fs::remove_dir(&root);
+
+        set_up.map_err(|why| why.context("Unable to set up the root of the filesystem"))
+    }
+
+    /// Grows a mounted volume: the image first, then the loop device and the
+    /// mapping on top of it, and finally the filesystem.
+    fn grow_mounted(
+        &self,
+        image: &Path,
+        header: Option<&Path>,
+        mount: &MountState,
+        size: u64,
+        key: &[u8],
+    ) -> DriverResult<()> {
+        self.set_image_len(image, size)?;
+        self.refresh_luks_device(&mount.mapper_name, image, header, key)?;
+
+        let device = PathBuf::from(format!("/dev/mapper/{}", mount.mapper_name));
+        let fs_type = FilesystemType::detect(&device).map_err(DriverError::Filesystem)?;
+        fs_type
+            .grow(&device, Path::new(&mount.mountpoint))
+            .map_err(DriverError::Filesystem)
+    }
+
+    /// Resizes a volume that isn't mounted through a private mapping. A
+    /// filesystem that can only grow while mounted is mounted on the volume's
+    /// mount dir for the duration.
+    fn resize_unmounted(
+        &self,
+        name: &str,
+        image: &Path,
+        header: Option<&Path>,
+        current: u64,
+        size: u64,
+        key: &[u8],
+    ) -> DriverResult<()> {
+        if size > current {
+            self.set_image_len(image, size)?;
+        }
+
+        self.with_temp_mapping(name, image, header, key, |device| {
+            let fs_type = FilesystemType::detect(device).map_err(DriverError::Filesystem)?;
+            if size < current {
+                let header =
+                    current - host::device_size(device).map_err(DriverError::DeviceMapper)?;
+                if size <= header {
+                    return Err(DriverError::InvalidOptions(format!(
+                        "{} bytes leaves no room for a filesystem",
+                        size
+                    )));
+                }
+                return fs_type
+                    .shrink(device, size - header)
+                    .map_err(DriverError::Filesystem);
+            }
+            if !fs_type.grows_mounted() {
+                return fs_type
+                    .grow(device, Path::new(""))
+                    .map_err(DriverError::Filesystem);
+            }
+
+            let mount_dir = self.mount_dir.join(name);
+            fs::create_dir_all(&mount_dir).map_err(|why| {
+                DriverError::Io(format!(
+                    "Unable to create mount dir {}: {}",
+                    mount_dir.display(),
+                    why
+                ))
+            })?;
+            let mounted = sys_mount::Mount::new(
+                device,
+                &mount_dir,
+                fs_type.to_string().as_str(),
+                sys_mount::MountFlags::empty(),
+                None,
+            )
+            .map_err(|why| {
+                DriverError::Mount(format!(
+                    "Unable to mount {} on {}: {}",
+                    device.display(),
+                    mount_dir.display(),
+                    why
+                ))
+            })
+            .and_then(|_| {
+                let grown = fs_type
+                    .grow(device, &mount_dir)
+                    .map_err(DriverError::Filesystem);
+                let unmounted = sys_mount::unmount(&mount_dir, sys_mount::UnmountFlags::empty())
+                    .map_err(|why| {
+                        DriverError::Mount(format!(
+                            "Unable to unmount {}: {}",
+                            mount_dir.display(),
+                            why
+                        ))
+                    });
+                grown.and(unmounted)
+            });
+            let _ = fs::remove_dir(&mount_dir);
+
+            mounted
+        })?;
+
+        if size < current {
+            self.set_image_len(image, size)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl LuksVolumeDriver {
+    /// Removes a volume so that it can't be recovered: the LUKS header,
+    /// detached or not, is erased and the keyfile overwritten before anything
+    /// is deleted, and the same goes for each of its snapshots. The whole
+    /// image is overwritten too if `shred` is set, or if the volume was
+    /// created with `shred=true`. A volume whose `volume.json` can't be read
+    /// can still be removed; its detached header is then looked for in the
+    /// usual places.
+    ///
+    /// A volume that is mounted or mapped is refused as busy, unless `force`
+    /// is set, in which case it is unmounted and closed first.
+    fn remove_volume(&self, name: &str, shred: bool, force: bool) -> DriverResult<()> {
+        validate_volume_name(name)?;
+        let volume_dir = &self.data_dir.join(name);
+        if !volume_dir.exists() {
+            return Err(DriverError::NotFound(format!("No volume named {}", name)));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        self.busy.check(name)?;
+        let mapper_name = Self::mapper_name(name)?;
+        let mount_dir = self.mount_dir.join(name);
+        let header = self.luks_header(name).unwrap_or_else(|why| {
+            warn!(
+                "Unable to read the metadata of volume {}, looking for its header: {}",
+                name, why
+            );
+            self.probe_luks_header(name)
+        });
+        let is_mounted = state.volumes.contains_key(name)
+            || host::mounted_paths()
+                .map_err(DriverError::Mount)?
+                .contains(&mount_dir);
+        let is_mapped = host::active_mappers()
+            .map_err(DriverError::DeviceMapper)?
+            .contains(&mapper_name);
+        if is_mounted || is_mapped {
+            if !force {
+                let mut ids: Vec<String> = state
+                    .volumes
+                    .get(name)
+                    .map(|mount| mount.ids.iter().cloned().collect())
+                    .unwrap_or_default();
+                ids.sort();
+                return Err(DriverError::Busy(if ids.is_empty() {
+                    format!("Volume {} is in use", name)
+                } else {
+                    format!("Volume {} is in use by {}", name, ids.join(", "))
+                }));
+            }
+
+            warn!("Forcibly releasing volume {} to remove it", name);
+            if is_mounted {
+                sys_mount::unmount(&mount_dir, sys_mount::UnmountFlags::FORCE)
+                    .or_else(|_| sys_mount::unmount(&mount_dir, sys_mount::UnmountFlags::DETACH))
+                    .map_err(|why| {
+                        DriverError::Mount(format!(
+                            "Unable to unmount {}: {}",
+                            mount_dir.display(),
+                            why
+                        ))
+                    })?;
+                let _ = fs::remove_dir(&mount_dir);
+            }
+            if is_mapped {
+                self.deactivate_luks_device(
+                    &mapper_name,
+                    &volume_dir.join("volume.img"),
+                    header.as_deref(),
+                )?;
+            }
+            state.volumes.remove(name);
+            self.save_state(&state);
+        }
+        let volume_img = &volume_dir.join("volume.img");
+        let key_file = &volume_dir.join("keyfile");
+        let shred = shred
+            || self
+                .volume_metadata(name)
+                .map(|metadata| metadata.shred)
+                .unwrap_or(false);
+
+        let do_steps = || -> DriverResult<()> {
+            match &header {
+                Some(header) if header.exists() => {
+                    erase::erase_header(&header).map_err(DriverError::Crypto)?;
+                    erase::shred_and_remove(&header).map_err(DriverError::Io)?;
+                }
+                Some(_) => {}
+                None if volume_img.exists() => {
+                    erase::erase_header(&volume_img).map_err(DriverError::Crypto)?;
+                }
+                None => {}
+            }
+            if shred && volume_img.exists() {
+                erase::shred(&volume_img).map_err(DriverError::Io)?;
+            }
+            if key_file.exists() {
+                erase::shred_and_remove(&key_file).map_err(DriverError::Io)?;
+            }
+            let snapshots = fs::read_dir(volume_dir.join(snapshot::SNAPSHOT_DIR))
+                .into_iter()
+                .flatten()
+                .filter_map(Result::ok);
+            for entry in snapshots {
+                snapshot::erase_snapshot(&entry.path(), shred)?;
+            }
+
+            fs::remove_dir_all(&volume_dir).map_err(|why| {
+                DriverError::Io(format!(
+                    "Unable to remove volume dir {}: {}",
+                    &volume_dir.to_str().unwrap(),
+                    why
+                ))
+            })
+        };
+
+        do_steps().map_err(|why| why.context(format!("Unable to remove volume {}", name)))
+    }
+}
+
+impl LuksVolumeDriver {
+    /// Where a new volume's detached header goes: in the header dir if the
+    /// daemon has one, and otherwise beside the image in `staging_dir`. Also
+    /// returns how `volume.json` records it, which for a header beside the
+    /// image is relative to the volume directory, since it moves with it.
+    fn detached_header_location(
+        &self,
+        name: &str,
+        staging_dir: &Path,
+    ) -> DriverResult<(PathBuf, String)> {
+        let dir = match &self.header_dir {
+            Some(dir) => dir,
+            None => return Ok((staging_dir.join(HEADER_FILE), HEADER_FILE.to_string())),
+        };
+
+        let header = dir.join(format!("{}{}", name, HEADER_SUFFIX));
+        if header.exists() {
+            return Err(DriverError::AlreadyExists(format!(
+                "A LUKS header for volume {} already exists at {}",
+                name,
+                header.display()
+            )));
+        }
+        let entry = header.to_string_lossy().into_owned();
+
+        Ok((header, entry))
+    }
+
+    /// Opens `image` on the temporary mapping of volume `name`, runs `f` on
+    /// the device and closes the mapping again, whether or not `f` succeeded.
+    fn with_temp_mapping<T, F>(
+        &self,
+        name: &str,
+        image: &Path,
+        header: Option<&Path>,
+        key: &[u8],
+        f: F,
+    ) -> DriverResult<T>
+    where
+        F: FnOnce(&Path) -> DriverResult<T>,
+    {
+        let mapper_name = Self::temp_mapper_name(name)?;
+        let device = self
+            .activate_luks_device(&mapper_name, image, header, key, ActivationFlags::default())
+            .map_err(|why| why.context("Unable to activate the LUKS disk image"))?;
+
+        let result = f(&device);
+        let closed = self
+            .deactivate_luks_device(&mapper_name, image, header)
+            .map_err(|why| why.context("Unable to deactivate the LUKS disk image"));
+
+        result.and_then(|value| closed.map(|_| value))
+    }
+
+    /// The last steps of building a volume in `staging_dir`: its keyfile and
+    /// `volume.json` are written, and it is renamed into place as
+    /// `volume_dir`.
+    fn commit_staged_volume(
+        &self,
+        staging_dir: &Path,
+        volume_dir: &Path,
+        key: &[u8],
+        metadata: &VolumeMetadata,
+    ) -> DriverResult<()> {
+        self.store_luks_key(staging_dir, key)?;
+        metadata.save(staging_dir).map_err(DriverError::Io)?;
+
+        fs::rename(staging_dir, volume_dir).map_err(|why| {
+            DriverError::Io(format!(
+                "Unable to move {} into place: {}",
+                volume_dir.display(),
+                why
+            ))
+        })?;
+        host::sync_dir(&self.data_dir).map_err(|why| {
+            DriverError::Io(format!(
+                "Unable to sync {}: {}",
+                self.data_dir.display(),
+                why
+            ))
+        })
+    }
+
+    /// Where a volume is mounted, if it is. The state lock is only held for
+    /// the lookup, so a caller that goes on to use the mountpoint claims the
+    /// volume first, which keeps it from being unmounted in the meantime.
+    fn mountpoint(&self, name: &str) -> Option<PathBuf> {
+        self.state
+            .lock()
+            .unwrap()
+            .volumes
+            .get(name)
+            .map(|mount| PathBuf::from(&mount.mountpoint))
+    }
+
+    /// Copies the files of a volume, each `(from, to)`, freezing its
+    /// filesystem for the duration if it is mounted at `mountpoint` so that
+    /// the copies are consistent with one another and with what was last
+    /// written.
+    fn copy_volume_files(
+        &self,
+        name: &str,
+        mountpoint: Option<&Path>,
+        files: &[(&Path, &Path)],
+    ) -> DriverResult<()> {
+        if let Some(mountpoint) = mountpoint {
+            info!("Freezing volume {} to copy it", name);
+            host::fsfreeze(mountpoint, true).map_err(|why| {
+                DriverError::Filesystem(format!("Unable to freeze volume {}: {}", name, why))
+            })?;
+        }
+
+        let copied = files.iter().try_for_each(|(from, to)| {
+            host::copy_file(from, to)
+                .map_err(|why| DriverError::io(format!("Unable to copy {}", from.display()), why))
+        });
+        if let Some(mountpoint) = mountpoint {
+            if let Err(why) = host::fsfreeze(mountpoint, false) {
+                error!("Unable to thaw volume {}: {}", name, why);
+            }
+        }
+
+        copied
+    }
+
+    /// Creates volume `name` as a copy of `source`. The image is reflinked or
+    /// copied while the source's filesystem is frozen, if it is mounted, and
+    /// the copy is then re-encrypted under a new volume key unless it is
+    /// cloned with `reencrypt=false`. Either way the clone gets a passphrase
+    /// and keyfile of its own, and new LUKS and filesystem UUIDs.
+    ///
+    /// Both volumes are claimed as busy for the duration rather than holding
+    /// the state lock, since copying and re-encrypting can take hours.
+    fn clone_volume(
+        &self,
+        name: &str,
+        source: &str,
+        mut opts: HashMap<String, String>,
+    ) -> DriverResult<()> {
+        validate_volume_name(source)?;
+        let reencrypt = options::take_option(&mut opts, "reencrypt")
+            .map_err(DriverError::InvalidOptions)?
+            .unwrap_or(true);
+        if !opts.is_empty() {
+            let mut unknown: Vec<String> = opts.keys().cloned().collect();
+            unknown.sort();
+            return Err(DriverError::InvalidOptions(format!(
+                "Only reencrypt can be given along with from, not {}",
+                unknown.join(", ")
+            )));
+        }
+
+        let source_dir = &self.data_dir.join(source);
+        let source_img = &source_dir.join("volume.img");
+        let size = fs::metadata(&source_img)
+            .map_err(|why| DriverError::io(format!("No volume named {}", source), why))?
+            .len();
+        let volume_dir = &self.data_dir.join(name);
+        if volume_dir.exists() {
+            return Err(DriverError::AlreadyExists(format!(
+                "A volume named {} already exists",
+                name
+            )));
+        }
+        let _claims = (self.busy.claim(source)?, self.busy.claim(name)?);
+        let mut metadata = self.volume_metadata(source)?;
+        if reencrypt && metadata.luks.integrity.is_some() {
+            return Err(DriverError::InvalidOptions(format!(
+                "Volume {} has integrity protection, which can't be re-encrypted, \
+                 so it can only be cloned with reencrypt=false",
+                source
+            )));
+        }
+        let fs_type: FilesystemType = metadata.fs_type.parse().map_err(|why| {
+            DriverError::Filesystem(format!("Invalid metadata for volume {}: {}", source, why))
+        })?;
+        let (preallocate, overcommit) = VolumeOptions::from_opts(metadata.options.clone())
+            .map_or((false, false), |options| {
+                (options.preallocate, options.overcommit)
+            });
+        self.check_free_space(name, size, overcommit)?;
+
+        let staging_dir = &self.data_dir.join(format!(".{}{}", name, STAGING_SUFFIX));
+        let volume_img = &staging_dir.join("volume.img");
+        let source_header = &metadata.luks.header_path(&source_dir);
+        let (header, header_entry) = match source_header {
+            Some(_) => {
+                let (header, entry) = self.detached_header_location(name, &staging_dir)?;
+                (Some(header), Some(entry))
+            }
+            None => (None, None),
+        };
+        let header = &header;
+
+        let mountpoint = self.mountpoint(source);
+        let mut source_key = self.get_luks_key(source)?;
+        let mut secret_key = self.hsm.random_bytes().map_err(|e| {
+            DriverError::from(e).context("Unable to generate random bytes for new LUKS key")
+        })?;
+        let luks_uuid = Uuid::new_v4();
+
+        let mut rollback = Rollback::new();
+        let mut do_steps = || -> DriverResult<()> {
+            create_staging_dir(staging_dir, header.as_deref(), &mut rollback)?;
+
+            let mut files = vec![(source_img.as_path(), volume_img.as_path())];
+            if let (Some(from), Some(to)) = (source_header, header) {
+                files.push((from.as_path(), to.as_path()));
+            }
+            self.copy_volume_files(source, mountpoint.as_deref(), &files)?;
+            if preallocate {
+                self.preallocate_image(&volume_img, size)?;
+            }
+
+            if reencrypt {
+                self.reencrypt_luks_device(&volume_img, header.as_deref(), &source_key)?;
+            }
+            let luks_header = header.as_ref().unwrap_or(volume_img);
+            self.change_luks_key(luks_header, &source_key, &secret_key)?;
+            self.set_luks_uuid(luks_header, &luks_uuid)?;
+            self.with_temp_mapping(name, volume_img, header.as_deref(), &secret_key, |device| {
+                fs_type
+                    .regenerate_uuid(device)
+                    .map_err(DriverError::Filesystem)
+            })?;
+
+            metadata.luks.uuid = Some(luks_uuid.to_string());
+            metadata.set_header(header_entry.clone());
+            metadata.created_at = Some(Utc::now().to_rfc3339());
+            metadata.hsm_backend = Some(self.hsm.backend().to_string());
+            metadata.cloned_from = Some(source.to_string());
+            self.commit_staged_volume(staging_dir, volume_dir, &secret_key, &metadata)
+        };
+
+        let result = do_steps();
+        crypto::wipe(&mut source_key);
+        crypto::wipe(&mut secret_key);
+        match result {
+            Ok(()) => {
+                rollback.commit();
+                Ok(())
+            }
+            Err(why) => {
+                rollback.rollback();
+                Err(why.context(format!("Unable to create volume {}", name)))
+            }
+        }
+    }
+
+    /// Runs fstrim on every mounted volume that allows discards, so that the
+    /// blocks their filesystems have freed are handed back by the sparse
+    /// images. Returns how much space that gave back to the data dir.
+    pub fn trim_volumes(&self) -> u64 {
+        // An fstrim can take minutes, so it runs on a copy of the mounts
+        // rather than under the state lock. A volume unmounted in the
+        // meantime just fails to trim.
+        let mounts: Vec<(String, PathBuf)> = self
+            .state
+            .lock()
+            .unwrap()
+            .volumes
+            .iter()
+            .map(|(name, mount)| (name.clone(), PathBuf::from(&mount.mountpoint)))
+            .collect();
+        let before = host::available_bytes(&self.data_dir);
+
+        let mut trimmed = 0;
+        for (name, mountpoint) in &mounts {
+            let discard = self
+                .volume_metadata(name)
+                .ok()
+                .and_then(|metadata| VolumeOptions::from_opts(metadata.options).ok())
+                .map_or(Discard::Off, |options| options.discard);
+            if discard == Discard::Off {
+                continue;
+            }
+
+            match host::fstrim(mountpoint) {
+                Ok(()) => trimmed += 1,
+                Err(why) => warn!("Unable to trim volume {}: {}", name, why),
+            }
+        }
+
+        let reclaimed = match (before, host::available_bytes(&self.data_dir)) {
+            (Ok(before), Ok(after)) => after.saturating_sub(before),
+            (Err(why), _) | (_, Err(why)) => {
+                warn!("Unable to measure the space reclaimed by fstrim: {}", why);
+                0
+            }
+        };
+        if trimmed > 0 {
+            info!(
+                "Trimmed {} volume(s), reclaiming {} bytes in {}",
+                trimmed,
+                reclaimed,
+                self.data_dir.display()
+            );
+        }
+
+        reclaimed
+    }
+}
+
+impl AdminDriver for LuksVolumeDriver {
+    fn resize(&self, name: String, size: String) -> DriverResult<()> {
+        validate_volume_name(&name)?;
+        let size = options::parse_size(&size)
+            .map_err(|why| DriverError::InvalidOptions(format!("Invalid size: {}", why)))?;
+        let volume_img = &self.data_dir.join(&name).join("volume.img");
+        let current = fs::metadata(&volume_img)
+            .map_err(|why| DriverError::NotFound(format!("Unable to find volume image: {}", why)))?
+            .len();
+        if size == current {
+            return Ok(());
+        }
+        let metadata = self.volume_metadata(&name).ok();
+        if let Some(luks) = metadata.as_ref().map(|metadata| &metadata.luks) {
+            if luks.integrity.is_some() {
+                return Err(DriverError::InvalidOptions(format!(
+                    "Volume {} has integrity protection, which can't be resized",
+                    name
+                )));
+            }
+            if let Some(sector_size) = luks.sector_size {
+                if size % u64::from(sector_size) != 0 {
+                    return Err(DriverError::InvalidOptions(format!(
+                        "Size must be a multiple of the {} byte sector size of volume {}",
+                        sector_size, name
+                    )));
+                }
+            }
+        }
+
+        let state = self.state.lock().unwrap();
+        self.busy.check(&name)?;
+        if size > current {
+            let options =
+                metadata.and_then(|metadata| VolumeOptions::from_opts(metadata.options).ok());
+            let preallocate = options
+                .as_ref()
+                .map_or(false, |options| options.preallocate);
+            let overcommit = options.as_ref().map_or(false, |options| options.overcommit);
+            self.check_free_space(&name, size - current, overcommit)?;
+            if preallocate {
+                self.preallocate_image(&volume_img, size)?;
+            }
+        }
+        let header = self.luks_header(&name)?;
+        let mut secret_key = self.get_luks_key(&name)?;
+        let result = match state.volumes.get(&name) {
+            Some(mount) if size > current => {
+                self.grow_mounted(&volume_img, header.as_deref(), mount, size, &secret_key)
+            }
+            Some(_) => Err(DriverError::Busy(
+                "volumes can only be shrunk while they are not mounted".to_string(),
+            )),
+            None => self.resize_unmounted(
+                &name,
+                &volume_img,
+                header.as_deref(),
+                current,
+                size,
+                &secret_key,
+            ),
+        };
+        crypto::wipe(&mut secret_key);
+
+        result.map_err(|why| why.context(format!("Unable to resize volume {}", name)))
+    }
+
+    fn remove(&self, name: String, shred: bool, force: bool) -> DriverResult<()> {
+        self.remove_volume(&name, shred, force)
+    }
+
+    fn create_snapshot(&self, name: String, snapshot: String) -> DriverResult<()> {
+        self.create_snapshot(&name, &snapshot)
+    }
+
+    fn list_snapshots(&self, name: String) -> DriverResult<Vec<Snapshot>> {
+        self.list_snapshots(&name)
+    }
+
+    fn restore_snapshot(&self, name: String, snapshot: String) -> DriverResult<()> {
+        self.restore_snapshot(&name, &snapshot)
+    }
+
+    fn remove_snapshot(&self, name: String, snapshot: String) -> DriverResult<()> {
+        self.remove_snapshot(&name, &snapshot)
+    }
+}
+
+impl VolumeDriver for LuksVolumeDriver {
+    fn create(&self, name: String, opts: Option<HashMap<String, String>>) -> DriverResult<()> {
+        validate_volume_name(&name)?;
+        let mut opts = opts.unwrap_or_default();
+        if let Some(source) = opts.remove("from") {
+            return self
+                .clone_volume(&name, &source, opts)
+                .map_err(|why| why.context(format!("Unable to clone volume {}", source)));
+        }
+        let _claim = self.busy.claim(&name)?;
+        let mut merged_opts = self.default_opts.clone();
+        merged_opts.extend(opts);
+        let options = VolumeOptions::from_opts(merged_opts.clone()).map_err(|why| {
+            DriverError::InvalidOptions(format!("Invalid options for volume {}: {}", name, why))
+        })?;
+        options.luks.check_kernel_support().map_err(|why| {
+            DriverError::InvalidOptions(format!("Invalid options for volume {}: {}", name, why))
+        })?;
+        let volume_dir = &self.data_dir.join(&name);
+        if volume_dir.exists() {
+            return Err(DriverError::AlreadyExists(format!(
+                "A volume named {} already exists",
+                name
+            )));
+        }
+        self.check_free_space(&name, options.size, options.overcommit)?;
+        let staging_dir = &self.data_dir.join(format!(".{}{}", name, STAGING_SUFFIX));
+        let volume_img = &staging_dir.join("volume.img");
+        let (header, header_entry) = if options.luks.detached_header {
+            let (header, entry) = self.detached_header_location(&name, &staging_dir)?;
+            (Some(header), Some(entry))
+        } else {
+            (None, None)
+        };
+        let header = &header;
+        let mut secret_key = self.hsm.random_bytes().map_err(|e| {
+            DriverError::from(e).context("Unable to generate random bytes for new LUKS key")
+        })?;
+
+        let mut rollback = Rollback::new();
+        let mut do_steps = || -> DriverResult<()> {
+            create_staging_dir(staging_dir, header.as_deref(), &mut rollback)?;
+
+            self.create_disk_image(&volume_img, options.size, options.preallocate)
+                .map_err(|why| {
+                    why.context(format!(
+                        "Couldn't create the LUKS disk image for the volume {}",
+                        name
+                    ))
+                })?;
+            rollback.push("create the disk image", move || {
+                fs::remove_file(&volume_img).map_err(|why| DriverError::Io(why.to_string()))
+            });
+
+            let luks_uuid = Uuid::new_v4();
+            self.format_luks_device(
+                &volume_img,
+                header.as_deref(),
+                &secret_key,
+                &luks_uuid,
+                &options.luks,
+            )
+            .map_err(|why| why.context("Unable to format LUKS header on the disk image"))?;
+
+            self.with_temp_mapping(
+                &name,
+                volume_img,
+                header.as_deref(),
+                &secret_key,
+                |device| {
+                    options.filesystem.format_device(device).map_err(|why| {
+                        DriverError::Filesystem(format!(
+                            "Unable to format the LUKS disk image: {}",
+                            why
+                        ))
+                    })?;
+                    if options.filesystem.has_root_options() {
+                        self.set_up_filesystem_root(&name, device, &options.filesystem)?;
+                    }
+
+                    Ok(())
+                },
+            )?;
+
+            let metadata = VolumeMetadata::new(
+                merged_opts.clone(),
+                &options,
+                luks_uuid.to_string(),
+                header_entry.clone(),
+                Utc::now().to_rfc3339(),
+                self.hsm.backend(),
+            );
+            self.commit_staged_volume(staging_dir, volume_dir, &secret_key, &metadata)
+        };
+
+        let result = do_steps();
+        crypto::wipe(&mut secret_key);
+        match result {
+            Ok(()) => {
+                rollback.commit();
+                Ok(())
+            }
+            Err(why) => {
+                rollback.rollback();
+                Err(why.context(format!("Unable to create volume {}", name)))
+            }
+        }
+    }
+    fn remove(&self, name: String) -> DriverResult<()> {
+        self.remove_volume(&name, false, false)
+    }
+    fn mount(&self, name: String, id: String) -> DriverResult<String> {
+        validate_volume_name(&name)?;
+        let volume_img = &self.data_dir.join(&name).join("volume.img");
+        let mount_dir = &self.mount_dir.join(&name);
+        let mapper_name = Self::mapper_name(&name)?;
+
+        let mut state = self.state.lock().unwrap();
+        if let Some(mount) = state.volumes.get_mut(&name) {
+            mount.ids.insert(id);
+            let mountpoint = mount.mountpoint.clone();
+            self.save_state(&state);
+            return Ok(mountpoint);
+        }
+        self.busy.check(&name)?;
+
+        let metadata = self.volume_metadata(&name)?;
+        let fs_type: FilesystemType = metadata.fs_type.parse().map_err(|why| {
+            DriverError::Filesystem(format!("Invalid metadata for volume {}: {}", name, why))
+        })?;
+        let options = VolumeOptions::from_opts(metadata.options).map_err(|why| {
+            DriverError::InvalidOptions(format!("Invalid options for volume {}: {}", name, why))
+        })?;
+        let mut mount_opts = options.mount;
+        mount_opts.merge(&self.mandatory_mount_opts);
+        if options.discard == Discard::Online {
+            mount_opts.add_data("discard");
+        }
+        // Discards from a filesystem mounted with mount_opts=discard would
+        // otherwise be dropped silently by dm-crypt.
+        let flags = ActivationFlags {
+            read_only: mount_opts.read_only(),
+            allow_discards: options.discard != Discard::Off || mount_opts.discard(),
+            ..options.activation
+        };
+        let volume_dir = self.data_dir.join(&name);
+        let header = &metadata.luks.header_path(&volume_dir);
+        let integrity = metadata.luks.integrity.is_some();
+        let mut secret_key = self.get_luks_key(&name)?;
+
+        let mut rollback = Rollback::new();
+        let mut do_steps = || -> DriverResult<String> {
+            if !mount_dir.exists() {
+                fs::create_dir_all(&mount_dir).map(|_| ()).map_err(|why| {
+                    DriverError::Io(format!(
+                        "Unable to create mount dir {}: {}",
+                        mount_dir.to_str().unwrap(),
+                        why
+                    ))
+                })?;
+                rollback.push("create the mount dir", move || {
+                    fs::remove_dir(&mount_dir).map_err(|why| DriverError::Io(why.to_string()))
+                });
+            }
+
+            let src = self
+                .activate_luks_device(
+                    &mapper_name,
+                    &volume_img,
+                    header.as_deref(),
+                    &secret_key,
+                    flags,
+                )
+                .map(|p| String::from(p.to_str().unwrap()))
+                .map_err(|why| why.context("Unable to open the LUKS volume"))?;
+            let opened = mapper_name.clone();
+            rollback.push("open the LUKS volume", move || {
+                self.deactivate_luks_device(&opened, &volume_img, header.as_deref())
+            });
+
+            sys_mount::Mount::new(
+                &src,
+                &mount_dir,
+                fs_type.to_string().as_str(),
+                mount_opts.flags,
+                mount_opts.data().as_ref().map(String::as_str),
+            )
+            .map_err(|why| {
+                let message = format!(
+                    "failed to get mount {} to {}: {}",
+                    &src,
+                    &mount_dir.to_str().unwrap(),
+                    why
+                );
+                if integrity && self.integrity_failed(&mapper_name, &why) {
+                    DriverError::Integrity(message)
+                } else {
+                    DriverError::Mount(message)
+                }
+            })
+            .map(|_| String::from(mount_dir.to_str().unwrap()))
+        };
+
+        let result = do_steps();
+        crypto::wipe(&mut secret_key);
+        match result {
+            Ok(mountpoint) => {
+                rollback.commit();
+                let mut ids = HashSet::new();
+                ids.insert(id);
+                state.volumes.insert(
+                    name,
+                    MountState {
+                        mapper_name,
+                        mountpoint: mountpoint.clone(),
+                        ids,
+                    },
+                );
+                self.save_state(&state);
+                Ok(mountpoint)
+            }
+            Err(why) => {
+                rollback.rollback();
+                Err(why.context(format!("Unable to mount the volume {}", name)))
+            }
+        }
+    }
+    fn path(&self, name: String) -> DriverResult<String> {
+        validate_volume_name(&name)?;
+        let mountpoint = self.mount_dir.join(&name).to_str().unwrap().to_owned();
+        fs::metadata(&mountpoint)
+            .map(|_| mountpoint)
+            .map_err(|why| {
+                DriverError::NotFound(format!(
+                    "Unable to get path for volume {}: {:?}",
+                    &name, why
+                ))
+            })
+    }
+    fn unmount(&self, name: String, id: String) -> DriverResult<()> {
+        validate_volume_name(&name)?;
+        let mnt_dir = &self.mount_dir.join(&name);
+        let volume_img = &self.data_dir.join(&name).join("volume.img");
+
+        let mut state = self.state.lock().unwrap();
+        let mount = match state.volumes.get_mut(&name) {
+            Some(mount) if mount.ids.contains(&id) => mount,
+            _ => {
+                return Err(DriverError::NotFound(format!(
+                    "Unable to unmount {}: it is not mounted for {}",
+                    name, id
+                )))
+            }
+        };
+        if mount.ids.len() > 1 {
+            mount.ids.remove(&id);
+            self.save_state(&state);
+            return Ok(());
+        }
+        self.busy.check(&name)?;
+        let mapper_name = mount.mapper_name.clone();
+        let header = self.luks_header(&name)?;
+
+        let do_steps = || -> DriverResult<()> {
+            sys_mount::unmount(&mnt_dir, sys_mount::UnmountFlags::FORCE)
+                .map_err(|why| {
+                    DriverError::Mount(format!(
+                        "Failed to unmount {}: {}",
+                        &mnt_dir.to_str().unwrap(),
+                        why
+                    ))
+                })
+                .map(|_| ())?;
+            self.deactivate_luks_device(&mapper_name, &volume_img, header.as_deref())?;
+            fs::remove_dir_all(&mnt_dir).map_err(|why| {
+                DriverError::Io(format!(
+                    "Unable to remove mount dir {}: {}",
+                    &mnt_dir.to_str().unwrap(),
+                    why
+                ))
+            })?;
+            Ok(())
+        };
+
+        do_steps().map_err(|why| why.context(format!("Unable to unmount {}", name)))?;
+        state.volumes.remove(&name);
+        self.save_state(&state);
+
+        Ok(())
+    }
+    fn get(&self, name: String) -> DriverResult<volume::Volume> {
+        validate_volume_name(&name)?;
+        let state = self.state.lock().unwrap();
+
+        self.describe_volume(&name, &state)
+            .map_err(|why| why.context("Unable to get volume info"))
+    }
+    fn list(&self) -> DriverResult<Vec<volume::Volume>> {
+        let state = self.state.lock().unwrap();
+        let volumes: Vec<volume::Volume> = fs::read_dir(Path::new(&self.data_dir))
+            .map_err(|why| {
+                DriverError::Io(format!(
+                    "Unable to read data dir {}: {}",
+                    self.data_dir.display(),
+                    why
+                ))
+            })?
+            .filter_map(Result::ok)
+            .filter(|f| f.metadata().map(|m| m.is_dir()).unwrap_or(false))
+            .filter_map(|f| f.file_name().into_string().ok())
+            .filter(|name| validate_volume_name(name).is_ok())
+            .map(|name| {
+                self.describe_volume(&name, &state).unwrap_or_else(|why| {
+                    warn!("Unable to describe volume {}: {}", name, why);
+                    volume::Volume {
+                        name,
+                        mountpoint: None,
+                        created_at: None,
+                        status: None,
+                    }
+                })
+            })
+            .collect();
+
+        Ok(volumes)
+    }
+}
+
+/// Builds an options map from `(key, value)` pairs, for testing the option
+/// parsers.
+#[cfg(test)]
+fn test_opts(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+    pairs
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+#[test]
+fn test_check_space() {
+    let data_dir = Path::new("/var/lib/luks-volumes");
+    let is_no_space = |result: DriverResult<()>| match result {
+        Err(DriverError::NoSpace(_)) => true,
+        _ => false,
+    };
+
+    assert!(check_space("db", 1 << 30, 1 << 30, false, data_dir).is_ok());
+    assert!(is_no_space(check_space(
+        "db",
+        2 << 30,
+        1 << 30,
+        false,
+        data_dir
+    )));
+    assert!(check_space("db", 2 << 30, 1 << 30, true, data_dir).is_ok());
+}
@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+
+use sys_mount::MountFlags;
+
+/// How a volume's filesystem is mounted. Flags known to the kernel become
+/// mount flags, and anything else, such as `discard` or `commit=60`, is
+/// handed to the filesystem as data.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MountOptions {
+    pub flags: MountFlags,
+    pub data: Vec<String>,
+}
+
+impl Default for MountOptions {
+    fn default() -> Self {
+        Self {
+            flags: MountFlags::empty(),
+            data: Vec::new(),
+        }
+    }
+}
+
+impl MountOptions {
+    pub fn from_opts(opts: &mut HashMap<String, String>) -> Result<Self, String> {
+        let mut options = match opts.remove("mount_opts") {
+            Some(value) => Self::parse(&value)?,
+            None => Self::default(),
+        };
+
+        // SELinux contexts contain commas, so they get an option of their own
+        // and are quoted in the data string.
+        if let Some(context) = opts.remove("selinux_context") {
+            if context.is_empty()
+                || !context
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "_-.:,".contains(c))
+            {
+                return Err(format!("Invalid SELinux context \"{}\"", context));
+            }
+            options.data.push(format!("context=\"{}\"", context));
+        }
+
+        Ok(options)
+    }
+
+    /// Parses a comma separated list of mount options, as given to
+    /// `mount -o`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut options = Self::default();
+        for option in value.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+            match flag(option) {
+                Some(flag) => options.flags |= flag,
+                None => {
+                    if !option
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "_-=.:".contains(c))
+                    {
+                        return Err(format!("Invalid mount option \"{}\"", option));
+                    }
+                    options.add_data(option);
+                }
+            }
+        }
+
+        Ok(options)
+    }
+
+    /// Adds the options that every volume must be mounted with.
+    pub fn merge(&mut self, mandatory: &Self) {
+        self.flags |= mandatory.flags;
+        for option in &mandatory.data {
+            self.add_data(option);
+        }
+    }
+
+    /// Adds a filesystem option, unless it is already there.
+    pub fn add_data(&mut self, option: &str) {
+        if !self.data.iter().any(|o| o == option) {
+            self.data.push(option.to_string());
+        }
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.flags.contains(MountFlags::RDONLY)
+    }
+
+    /// Whether the filesystem issues discards as it frees blocks.
+    pub fn discard(&self) -> bool {
+        self.data.iter().any(|o| o == "discard")
+    }
+
+    /// The data string for `mount(2)`, if there is any.
+    pub fn data(&self) -> Option<String> {
+        if self.data.is_empty() {
+            None
+        } else {
+            Some(self.data.join(","))
+        }
+    }
+}
+
+fn flag(option: &str) -> Option<MountFlags> {
+    match option {
+        "ro" => Some(MountFlags::RDONLY),
+        "noexec" => Some(MountFlags::NOEXEC),
+        "nosuid" => Some(MountFlags::NOSUID),
+        "nodev" => Some(MountFlags::NODEV),
+        "noatime" => Some(MountFlags::NOATIME),
+        "nodiratime" => Some(MountFlags::NODIRATIME),
+        "relatime" => Some(MountFlags::RELATIME),
+        "sync" => Some(MountFlags::SYNCHRONOUS),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_mount_options() {
+    let options = MountOptions::parse("ro,noexec,discard,commit=60").unwrap();
+    assert!(options.read_only());
+    assert!(options.discard());
+    assert!(options.flags.contains(MountFlags::NOEXEC));
+    assert_eq!(options.data(), Some("discard,commit=60".to_string()));
+
+    let mut options = MountOptions::parse("noatime").unwrap();
+    options.merge(&MountOptions::parse("nodev,nosuid").unwrap());
+    assert!(options
+        .flags
+        .contains(MountFlags::NOATIME | MountFlags::NODEV | MountFlags::NOSUID));
+    assert!(!options.read_only());
+    assert!(!options.discard());
+    assert_eq!(options.data(), None);
+
+    let mut opts = HashMap::new();
+    opts.insert(
+        "selinux_context".to_string(),
+        "system_u:object_r:container_file_t:s0:c1,c2".to_string(),
+    );
+    assert_eq!(
+        MountOptions::from_opts(&mut opts).unwrap().data(),
+        Some("context=\"system_u:object_r:container_file_t:s0:c1,c2\"".to_string())
+    );
+
+    assert!(MountOptions::parse("data=journal").is_ok());
+    assert!(MountOptions::parse("uid=0,bind /etc").is_err());
+}
@@ -0,0 +1,214 @@
+use super::filesystem::FilesystemOptions;
+use super::mount::MountOptions;
+use super::params::{ActivationFlags, Discard, LuksOptions};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+const DEFAULT_SIZE: u64 = 1 << 30;
+const SECTOR_SIZE: u64 = 512;
+/// User labels are given as `label.<key>=<value>` options.
+const LABEL_PREFIX: &str = "label.";
+
+pub struct VolumeOptions {
+    pub size: u64,
+    pub filesystem: FilesystemOptions,
+    pub luks: LuksOptions,
+    pub mount: MountOptions,
+    /// Overwrite the whole image when the volume is removed.
+    pub shred: bool,
+    /// Reserve the whole image up front rather than leaving it sparse.
+    pub preallocate: bool,
+    /// Let a sparse image be bigger than the space left in the data dir,
+    /// rather than refusing it. Off by default, as the volume's filesystem
+    /// will see I/O errors if the data dir fills up under it.
+    pub overcommit: bool,
+    pub discard: Discard,
+    /// The dm-crypt performance flags the volume is activated with.
+    pub activation: ActivationFlags,
+    pub labels: HashMap<String, String>,
+}
+
+impl Default for VolumeOptions {
+    fn default() -> Self {
+        Self {
+            size: DEFAULT_SIZE,
+            filesystem: FilesystemOptions::default(),
+            luks: LuksOptions::default(),
+            mount: MountOptions::default(),
+            shred: false,
+            preallocate: false,
+            overcommit: false,
+            discard: Discard::Off,
+            activation: ActivationFlags::default(),
+            labels: HashMap::new(),
+        }
+    }
+}
+
+impl VolumeOptions {
+    pub fn from_opts(mut opts: HashMap<String, String>) -> Result<Self, String> {
+        let mut options = Self::default();
+
+        if let Some(size) = opts.remove("size") {
+            options.size = parse_size(&size)?;
+        }
+        options.filesystem = FilesystemOptions::from_opts(&mut opts)?;
+        options.luks = LuksOptions::from_opts(&mut opts)?;
+        options.mount = MountOptions::from_opts(&mut opts)?;
+        options.shred = take_option(&mut opts, "shred")?.unwrap_or(false);
+        options.preallocate = take_option(&mut opts, "preallocate")?.unwrap_or(false);
+        options.overcommit = take_option(&mut opts, "overcommit")?.unwrap_or(false);
+        if options.overcommit && options.preallocate {
+            return Err("overcommit can't be used with preallocate".to_string());
+        }
+        // The integrity wipe writes every sector, so such images are never
+        // sparse.
+        if options.overcommit && options.luks.integrity.is_some() {
+            return Err("overcommit can't be used with integrity".to_string());
+        }
+        options.discard = take_option(&mut opts, "discard")?.unwrap_or(Discard::Off);
+        options.activation = ActivationFlags::from_opts(&mut opts)?;
+        if let Some(sector_size) = options.luks.sector_size {
+            if options.size % u64::from(sector_size) != 0 {
+                return Err(format!(
+                    "Size must be a multiple of the {} byte sector size",
+                    sector_size
+                ));
+            }
+        }
+
+        let label_keys: Vec<String> = opts
+            .keys()
+            .filter(|key| key.starts_with(LABEL_PREFIX))
+            .cloned()
+            .collect();
+        for key in label_keys {
+            let value = opts.remove(&key).unwrap_or_default();
+            let label = &key[LABEL_PREFIX.len()..];
+            if label.is_empty() {
+                return Err(format!("Option {} is missing a label name", key));
+            }
+            options.labels.insert(label.to_string(), value);
+        }
+
+        if !opts.is_empty() {
+            let mut unknown: Vec<String> = opts.keys().cloned().collect();
+            unknown.sort();
+            return Err(format!("Unknown volume option(s): {}", unknown.join(", ")));
+        }
+
+        Ok(options)
+    }
+}
+
+/// Removes `key` from the options map and parses its value, if present.
+pub fn take_option<T>(opts: &mut HashMap<String, String>, key: &str) -> Result<Option<T>, String>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match opts.remove(key) {
+        Some(value) => value
+            .parse::<T>()
+            .map(Some)
+            .map_err(|why| format!("Invalid value \"{}\" for option {}: {}", value, key, why)),
+        None => Ok(None),
+    }
+}
+
+/// Parses a human readable size such as `500M`, `20G` or `1T` into bytes. Units
+/// are binary, so `1G` is 1024^3 bytes, and a bare number is taken as bytes.
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or_else(|| value.len());
+    let (digits, unit) = value.split_at(split);
+
+    let number = digits
+        .parse::<u64>()
+        .map_err(|_| format!("Invalid size \"{}\"", value))?;
+    let multiplier: u64 = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => return Err(format!("Invalid size unit in \"{}\"", value)),
+    };
+
+    let size = number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("Size \"{}\" is too large", value))?;
+    if size == 0 {
+        return Err("Size must be greater than zero".to_string());
+    }
+    if size % SECTOR_SIZE != 0 {
+        return Err(format!(
+            "Size \"{}\" must be a multiple of {} bytes",
+            value, SECTOR_SIZE
+        ));
+    }
+
+    Ok(size)
+}
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("500M"), Ok(500 << 20));
+    assert_eq!(parse_size("20G"), Ok(20 << 30));
+    assert_eq!(parse_size("1t"), Ok(1 << 40));
+    assert_eq!(parse_size("4096"), Ok(4096));
+
+    assert!(parse_size("0G").is_err());
+    assert!(parse_size("").is_err());
+    assert!(parse_size("G").is_err());
+    assert!(parse_size("1.5G").is_err());
+    assert!(parse_size("10X").is_err());
+    assert!(parse_size("1000").is_err());
+    assert!(parse_size("99999999999T").is_err());
+}
+
+#[test]
+fn test_sector_size_and_perf_flags() {
+    let parse = |pairs: &[(&str, &str)]| VolumeOptions::from_opts(super::test_opts(pairs));
+
+    let options = parse(&[
+        ("size", "1G"),
+        ("sector_size", "4096"),
+        ("no_read_workqueue", "true"),
+        ("no_write_workqueue", "true"),
+        ("same_cpu_crypt", "true"),
+    ])
+    .unwrap();
+    assert_eq!(options.luks.sector_size, Some(4096));
+    assert_eq!(
+        options.activation.cryptsetup_args(),
+        vec![
+            "--perf-no_read_workqueue",
+            "--perf-no_write_workqueue",
+            "--perf-same_cpu_crypt"
+        ]
+    );
+    assert!(!options.activation.read_only && !options.activation.allow_discards);
+
+    let defaults = parse(&[]).unwrap();
+    assert_eq!(defaults.luks.sector_size, None);
+    assert_eq!(defaults.activation, ActivationFlags::default());
+
+    assert!(parse(&[("size", "2560"), ("sector_size", "4096")]).is_err());
+    assert!(parse(&[("size", "2560"), ("sector_size", "512")]).is_ok());
+    assert!(parse(&[("same_cpu_crypt", "maybe")]).is_err());
+}
+
+#[test]
+fn test_overcommit() {
+    let parse = |pairs: &[(&str, &str)]| VolumeOptions::from_opts(super::test_opts(pairs));
+
+    assert!(!parse(&[]).unwrap().overcommit);
+    assert!(parse(&[("overcommit", "true")]).unwrap().overcommit);
+    assert!(parse(&[("overcommit", "true"), ("preallocate", "true")]).is_err());
+    assert!(parse(&[("overcommit", "true"), ("integrity", "aead")]).is_err());
+}
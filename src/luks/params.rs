@@ -0,0 +1,505 @@
+use super::cipher::{self, CipherOptions};
+use super::options::take_option;
+
+use cryptsetup_rs::crypt_pbkdf_algo_type;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+const DEFAULT_ITERATION_TIME_MS: u64 = 5000;
+const MIN_PBKDF_MEMORY_KB: u32 = 32;
+const MAX_PBKDF_MEMORY_KB: u32 = 4 * 1024 * 1024;
+const MIN_PBKDF2_ITERATIONS: u32 = 1000;
+const MIN_ARGON2_ITERATIONS: u32 = 4;
+const AEAD_CIPHER_MODE: &str = "gcm-random";
+const MIN_SECTOR_SIZE: u32 = 512;
+const MAX_SECTOR_SIZE: u32 = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LuksVersion {
+    Luks1,
+    Luks2,
+}
+
+impl FromStr for LuksVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1" | "luks1" => Ok(Self::Luks1),
+            "2" | "luks2" => Ok(Self::Luks2),
+            _ => Err(format!(
+                "Unsupported LUKS version \"{}\", expected 1 or 2",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for LuksVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Luks1 => "luks1",
+                Self::Luks2 => "luks2",
+            }
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pbkdf {
+    Argon2id,
+    Argon2i,
+    Pbkdf2,
+}
+
+impl Pbkdf {
+    pub fn algo_type(self) -> crypt_pbkdf_algo_type {
+        match self {
+            Self::Argon2id => crypt_pbkdf_algo_type::argon2id,
+            Self::Argon2i => crypt_pbkdf_algo_type::argon2i,
+            Self::Pbkdf2 => crypt_pbkdf_algo_type::pbkdf2,
+        }
+    }
+
+    fn is_argon2(self) -> bool {
+        self != Self::Pbkdf2
+    }
+}
+
+impl FromStr for Pbkdf {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "argon2id" => Ok(Self::Argon2id),
+            "argon2i" => Ok(Self::Argon2i),
+            "pbkdf2" => Ok(Self::Pbkdf2),
+            _ => Err(format!(
+                "Unsupported PBKDF \"{}\", expected one of argon2id, argon2i or pbkdf2",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Pbkdf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Argon2id => "argon2id",
+                Self::Argon2i => "argon2i",
+                Self::Pbkdf2 => "pbkdf2",
+            }
+        )
+    }
+}
+
+/// Authenticated encryption through dm-integrity, which LUKS2 can set up
+/// beneath dm-crypt so that tampering with the image is detected on read.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Integrity {
+    HmacSha256,
+    HmacSha512,
+    /// The cipher authenticates the data itself, as `aes-gcm-random` does.
+    Aead,
+}
+
+impl Integrity {
+    /// The size of the integrity key, which cryptsetup expects to be
+    /// included in the volume key size.
+    fn key_size(self) -> usize {
+        match self {
+            Self::HmacSha256 => 256,
+            Self::HmacSha512 => 512,
+            Self::Aead => 0,
+        }
+    }
+
+    /// The kernel hash behind an HMAC integrity mode.
+    pub fn kernel_hash_name(self) -> Option<&'static str> {
+        match self {
+            Self::HmacSha256 => Some("hmac(sha256)"),
+            Self::HmacSha512 => Some("hmac(sha512)"),
+            Self::Aead => None,
+        }
+    }
+}
+
+impl FromStr for Integrity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hmac-sha256" => Ok(Self::HmacSha256),
+            "hmac-sha512" => Ok(Self::HmacSha512),
+            "aead" => Ok(Self::Aead),
+            _ => Err(format!(
+                "Unsupported integrity \"{}\", expected one of hmac-sha256, hmac-sha512 or aead",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Integrity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::HmacSha256 => "hmac-sha256",
+                Self::HmacSha512 => "hmac-sha512",
+                Self::Aead => "aead",
+            }
+        )
+    }
+}
+
+/// Whether blocks freed by the filesystem are passed down to the image, so
+/// that a sparse image gives the space back. It lets an observer of the image
+/// see which blocks are unused, which is why it is off by default.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Discard {
+    Off,
+    /// The filesystem is mounted with `discard` and frees blocks as it goes.
+    Online,
+    /// Blocks are freed in batches by the driver's periodic fstrim.
+    Periodic,
+}
+
+impl FromStr for Discard {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "false" | "off" => Ok(Self::Off),
+            "true" | "online" => Ok(Self::Online),
+            "periodic" => Ok(Self::Periodic),
+            _ => Err(format!(
+                "Unsupported discard \"{}\", expected one of off, online or periodic",
+                s
+            )),
+        }
+    }
+}
+
+/// How a LUKS device's dm-crypt mapping is opened. The dm-crypt performance
+/// flags come from the volume's options, and the rest from how it is mounted.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ActivationFlags {
+    pub read_only: bool,
+    pub allow_discards: bool,
+    /// Decrypt reads in the context of the request rather than a workqueue.
+    pub no_read_workqueue: bool,
+    /// Encrypt writes in the context of the request rather than a workqueue.
+    pub no_write_workqueue: bool,
+    /// Encrypt on the CPU that submitted the I/O.
+    pub same_cpu_crypt: bool,
+}
+
+impl ActivationFlags {
+    /// Reads the dm-crypt performance flags, `no_read_workqueue`,
+    /// `no_write_workqueue` and `same_cpu_crypt`.
+    pub fn from_opts(opts: &mut HashMap<String, String>) -> Result<Self, String> {
+        Ok(Self {
+            no_read_workqueue: take_option(opts, "no_read_workqueue")?.unwrap_or(false),
+            no_write_workqueue: take_option(opts, "no_write_workqueue")?.unwrap_or(false),
+            same_cpu_crypt: take_option(opts, "same_cpu_crypt")?.unwrap_or(false),
+            ..Self::default()
+        })
+    }
+
+    /// The `cryptsetup open` arguments that set these flags.
+    pub fn cryptsetup_args(self) -> Vec<&'static str> {
+        let mut args = vec![];
+        if self.read_only {
+            args.push("--readonly");
+        }
+        if self.allow_discards {
+            args.push("--allow-discards");
+        }
+        if self.no_read_workqueue {
+            args.push("--perf-no_read_workqueue");
+        }
+        if self.no_write_workqueue {
+            args.push("--perf-no_write_workqueue");
+        }
+        if self.same_cpu_crypt {
+            args.push("--perf-same_cpu_crypt");
+        }
+
+        args
+    }
+}
+
+/// How the LUKS header should be laid out when a volume is formatted. New
+/// volumes default to LUKS2 with Argon2id; LUKS1 only supports PBKDF2.
+pub struct LuksOptions {
+    pub version: LuksVersion,
+    pub cipher: CipherOptions,
+    pub pbkdf: Pbkdf,
+    /// Argon2 memory cost in KiB.
+    pub pbkdf_memory: Option<u32>,
+    /// Forced PBKDF time cost. When unset the cost is benchmarked so that
+    /// unlocking takes `iteration_time` milliseconds.
+    pub pbkdf_iterations: Option<u32>,
+    pub iteration_time: u64,
+    /// Keep the header in a file of its own, so that the image holds
+    /// nothing but ciphertext.
+    pub detached_header: bool,
+    pub integrity: Option<Integrity>,
+    /// The encryption sector size in bytes, when not the default of 512.
+    pub sector_size: Option<u32>,
+}
+
+impl Default for LuksOptions {
+    fn default() -> Self {
+        Self {
+            version: LuksVersion::Luks2,
+            cipher: CipherOptions::default(),
+            pbkdf: Pbkdf::Argon2id,
+            pbkdf_memory: None,
+            pbkdf_iterations: None,
+            iteration_time: DEFAULT_ITERATION_TIME_MS,
+            detached_header: false,
+            integrity: None,
+            sector_size: None,
+        }
+    }
+}
+
+impl LuksOptions {
+    pub fn from_opts(opts: &mut HashMap<String, String>) -> Result<Self, String> {
+        let version = take_option(opts, "luks_version")?.unwrap_or(LuksVersion::Luks2);
+        let default_pbkdf = match version {
+            LuksVersion::Luks1 => Pbkdf::Pbkdf2,
+            LuksVersion::Luks2 => Pbkdf::Argon2id,
+        };
+
+        let integrity = take_option(opts, "integrity")?;
+        // AEAD needs an authenticating cipher, so that is the default for it.
+        let aead_default = integrity == Some(Integrity::Aead)
+            && !opts.contains_key("cipher")
+            && !opts.contains_key("cipher_mode");
+        let mut cipher = CipherOptions::from_opts(opts)?;
+        if aead_default {
+            cipher.cipher_mode = AEAD_CIPHER_MODE.to_string();
+        }
+
+        let options = Self {
+            version,
+            cipher,
+            pbkdf: take_option(opts, "pbkdf")?.unwrap_or(default_pbkdf),
+            pbkdf_memory: take_option(opts, "pbkdf_memory")?,
+            pbkdf_iterations: take_option(opts, "pbkdf_iterations")?,
+            iteration_time: DEFAULT_ITERATION_TIME_MS,
+            detached_header: take_option(opts, "detached_header")?.unwrap_or(false),
+            integrity,
+            sector_size: take_option(opts, "sector_size")?,
+        };
+        options.validate()?;
+
+        Ok(options)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.version == LuksVersion::Luks1 {
+            if self.pbkdf != Pbkdf::Pbkdf2 {
+                return Err(format!("{} requires luks_version=2", self.pbkdf));
+            }
+            if self.pbkdf_iterations.is_some() {
+                return Err("pbkdf_iterations requires luks_version=2".to_string());
+            }
+            if self.integrity.is_some() {
+                return Err("integrity requires luks_version=2".to_string());
+            }
+            if self.sector_size.is_some() {
+                return Err("sector_size requires luks_version=2".to_string());
+            }
+        }
+
+        if let Some(sector_size) = self.sector_size {
+            if !sector_size.is_power_of_two()
+                || sector_size < MIN_SECTOR_SIZE
+                || sector_size > MAX_SECTOR_SIZE
+            {
+                return Err(format!(
+                    "sector_size must be a power of two between {} and {}",
+                    MIN_SECTOR_SIZE, MAX_SECTOR_SIZE
+                ));
+            }
+        }
+
+        let aead = self.integrity == Some(Integrity::Aead);
+        if aead && !self.cipher.is_aead() {
+            return Err(format!(
+                "integrity=aead requires an AEAD cipher mode such as {}, not {}",
+                AEAD_CIPHER_MODE, self.cipher.cipher_mode
+            ));
+        }
+        if !aead && self.cipher.is_aead() {
+            return Err(format!(
+                "cipher_mode {} requires integrity=aead",
+                self.cipher.cipher_mode
+            ));
+        }
+
+        if let Some(memory) = self.pbkdf_memory {
+            if !self.pbkdf.is_argon2() {
+                return Err(format!("pbkdf_memory is not supported for {}", self.pbkdf));
+            }
+            if memory < MIN_PBKDF_MEMORY_KB || memory > MAX_PBKDF_MEMORY_KB {
+                return Err(format!(
+                    "pbkdf_memory must be between {} and {} KiB",
+                    MIN_PBKDF_MEMORY_KB, MAX_PBKDF_MEMORY_KB
+                ));
+            }
+        }
+
+        if let Some(iterations) = self.pbkdf_iterations {
+            let min = if self.pbkdf.is_argon2() {
+                MIN_ARGON2_ITERATIONS
+            } else {
+                MIN_PBKDF2_ITERATIONS
+            };
+            if iterations < min {
+                return Err(format!(
+                    "pbkdf_iterations must be at least {} for {}",
+                    min, self.pbkdf
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether the header needs features that only `cryptsetup` can format.
+    pub fn needs_cryptsetup(&self) -> bool {
+        self.integrity.is_some() || self.sector_size.is_some()
+    }
+
+    /// Checks that the kernel provides the cipher and, for HMAC integrity,
+    /// the keyed hash.
+    pub fn check_kernel_support(&self) -> Result<(), String> {
+        self.cipher.check_kernel_support()?;
+        if let Some(hash) = self.integrity.and_then(Integrity::kernel_hash_name) {
+            if !cipher::kernel_supports("hash", hash, None) {
+                return Err(format!("The kernel does not support the hash {}", hash));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The `cryptsetup luksFormat` arguments that lay out a header as these
+    /// options describe, for formats the bindings can't do.
+    pub fn cryptsetup_format_args(&self) -> Vec<String> {
+        let cipher = &self.cipher;
+        let integrity_key_size = self.integrity.map_or(0, Integrity::key_size);
+        let mut args = vec![
+            format!("--type={}", self.version),
+            format!("--cipher={}-{}", cipher.cipher, cipher.cipher_mode),
+            format!("--key-size={}", cipher.key_size + integrity_key_size),
+            format!("--hash={}", cipher.hash),
+            format!("--iter-time={}", self.iteration_time),
+            "--use-urandom".to_string(),
+        ];
+        if self.version == LuksVersion::Luks2 {
+            args.push(format!("--pbkdf={}", self.pbkdf));
+        }
+        if let Some(memory) = self.pbkdf_memory {
+            args.push(format!("--pbkdf-memory={}", memory));
+        }
+        if let Some(iterations) = self.pbkdf_iterations {
+            args.push(format!("--pbkdf-force-iterations={}", iterations));
+        }
+        if let Some(integrity) = self.integrity {
+            args.push(format!("--integrity={}", integrity));
+        }
+        if let Some(sector_size) = self.sector_size {
+            args.push(format!("--sector-size={}", sector_size));
+        }
+
+        args
+    }
+}
+
+#[test]
+fn test_luks_options_validation() {
+    let parse = |pairs: &[(&str, &str)]| LuksOptions::from_opts(&mut super::test_opts(pairs));
+
+    let defaults = parse(&[]).unwrap();
+    assert_eq!(defaults.version, LuksVersion::Luks2);
+    assert_eq!(defaults.pbkdf, Pbkdf::Argon2id);
+    assert_eq!(
+        parse(&[("luks_version", "1")]).unwrap().pbkdf,
+        Pbkdf::Pbkdf2
+    );
+    assert!(parse(&[("pbkdf", "argon2i"), ("pbkdf_memory", "1048576")]).is_ok());
+
+    assert!(parse(&[("luks_version", "3")]).is_err());
+    assert!(parse(&[("luks_version", "1"), ("pbkdf", "argon2id")]).is_err());
+    assert!(parse(&[("pbkdf", "pbkdf2"), ("pbkdf_memory", "65536")]).is_err());
+    assert!(parse(&[("pbkdf", "pbkdf2"), ("pbkdf_iterations", "10")]).is_err());
+
+    let args = parse(&[("pbkdf_memory", "65536"), ("detached_header", "true")])
+        .unwrap()
+        .cryptsetup_format_args();
+    assert!(args.contains(&"--type=luks2".to_string()));
+    assert!(args.contains(&"--pbkdf=argon2id".to_string()));
+    assert!(args.contains(&"--pbkdf-memory=65536".to_string()));
+    assert!(!parse(&[("luks_version", "1")])
+        .unwrap()
+        .cryptsetup_format_args()
+        .iter()
+        .any(|arg| arg.starts_with("--pbkdf")));
+
+    let aead = parse(&[("integrity", "aead")]).unwrap();
+    assert_eq!(aead.cipher.cipher_mode, "gcm-random");
+    let hmac = parse(&[("integrity", "hmac-sha256"), ("key_size", "512")]).unwrap();
+    assert!(hmac
+        .cryptsetup_format_args()
+        .contains(&"--key-size=768".to_string()));
+    assert!(parse(&[("integrity", "aead"), ("cipher", "aes-xts-plain64")]).is_err());
+    assert!(parse(&[("cipher_mode", "gcm-random")]).is_err());
+    assert!(parse(&[("integrity", "hmac-sha256"), ("luks_version", "1")]).is_err());
+    assert!(parse(&[("integrity", "crc32")]).is_err());
+
+    assert!(parse(&[("sector_size", "4096")])
+        .unwrap()
+        .cryptsetup_format_args()
+        .contains(&"--sector-size=4096".to_string()));
+    assert!(parse(&[("sector_size", "4096"), ("luks_version", "1")]).is_err());
+    assert!(parse(&[("sector_size", "3000")]).is_err());
+    assert!(parse(&[("sector_size", "8192")]).is_err());
+}
+
+#[test]
+fn test_activation_flags() {
+    assert_eq!("periodic".parse::<Discard>(), Ok(Discard::Periodic));
+    assert_eq!("true".parse::<Discard>(), Ok(Discard::Online));
+    assert!("sometimes".parse::<Discard>().is_err());
+
+    let flags = ActivationFlags {
+        read_only: true,
+        allow_discards: true,
+        ..ActivationFlags::default()
+    };
+    assert_eq!(
+        flags.cryptsetup_args(),
+        vec!["--readonly", "--allow-discards"]
+    );
+    assert!(ActivationFlags::default().cryptsetup_args().is_empty());
+
+    let mut opts = super::test_opts(&[("no_read_workqueue", "true"), ("same_cpu_crypt", "false")]);
+    let flags = ActivationFlags::from_opts(&mut opts).unwrap();
+    assert!(opts.is_empty());
+    assert_eq!(flags.cryptsetup_args(), vec!["--perf-no_read_workqueue"]);
+}
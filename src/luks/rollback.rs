@@ -0,0 +1,79 @@
+use crate::plugin::error::DriverResult;
+
+use log::{error, info};
+
+type Undo<'a> = Box<dyn FnOnce() -> DriverResult<()> + 'a>;
+
+/// Records how to undo each completed step of a multi-step operation, so
+/// that a failure part way through can put the host back as it was.
+pub struct Rollback<'a> {
+    steps: Vec<(String, Undo<'a>)>,
+}
+
+impl<'a> Rollback<'a> {
+    pub fn new() -> Self {
+        Self { steps: Vec::new() }
+    }
+
+    /// Records how to undo a step that has just completed.
+    pub fn push<F>(&mut self, description: &str, undo: F)
+    where
+        F: FnOnce() -> DriverResult<()> + 'a,
+    {
+        self.steps.push((description.to_string(), Box::new(undo)));
+    }
+
+    /// Forgets the most recent step, for when the operation itself has
+    /// undone it as part of its normal course.
+    pub fn pop(&mut self) {
+        self.steps.pop();
+    }
+
+    /// The operation went through, so nothing is to be undone.
+    pub fn commit(mut self) {
+        self.steps.clear();
+    }
+
+    /// Undoes every recorded step, most recent first. Failures are logged
+    /// rather than returned so that the error which caused the rollback is
+    /// the one reported.
+    pub fn rollback(mut self) {
+        while let Some((description, undo)) = self.steps.pop() {
+            match undo() {
+                Ok(()) => info!("Rolled back: {}", description),
+                Err(why) => error!("Unable to roll back {}: {}", description, why),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_rollback_in_reverse() {
+    use std::cell::RefCell;
+
+    let undone = RefCell::new(Vec::new());
+    let mut rollback = Rollback::new();
+    rollback.push("first", || {
+        undone.borrow_mut().push(1);
+        Ok(())
+    });
+    rollback.push("second", || {
+        undone.borrow_mut().push(2);
+        Ok(())
+    });
+    rollback.push("third", || {
+        undone.borrow_mut().push(3);
+        Ok(())
+    });
+    rollback.pop();
+    rollback.rollback();
+    assert_eq!(*undone.borrow(), vec![2, 1]);
+
+    let mut rollback = Rollback::new();
+    rollback.push("first", || {
+        undone.borrow_mut().push(4);
+        Ok(())
+    });
+    rollback.commit();
+    assert_eq!(*undone.borrow(), vec![2, 1]);
+}
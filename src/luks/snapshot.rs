@@ -0,0 +1,475 @@
+use super::erase;
+use super::host;
+use super::metadata::HEADER_FILE;
+use super::options::VolumeOptions;
+use super::rollback::Rollback;
+use super::{LuksVolumeDriver, STAGING_SUFFIX};
+use crate::crypto;
+use crate::plugin::admin::Snapshot;
+use crate::plugin::error::{DriverError, DriverResult};
+use crate::plugin::name::validate_volume_name;
+
+use chrono::Utc;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Snapshots live in this directory of their volume, one directory each.
+pub const SNAPSHOT_DIR: &str = "snapshots";
+const SNAPSHOT_FILE: &str = "snapshot.json";
+const RESTORING_EXTENSION: &str = "restoring";
+const PREVIOUS_EXTENSION: &str = "previous";
+
+/// What is known about a snapshot besides its files, kept in `snapshot.json`.
+#[derive(Serialize, Deserialize)]
+pub struct SnapshotMetadata {
+    pub created_at: String,
+    /// The size of the volume's image when the snapshot was taken.
+    pub size: u64,
+    /// Whether the snapshot holds a copy of a detached header.
+    #[serde(default)]
+    pub detached_header: bool,
+}
+
+impl SnapshotMetadata {
+    pub fn load(snapshot_dir: &Path) -> Result<Self, String> {
+        let path = snapshot_dir.join(SNAPSHOT_FILE);
+        let contents =
+            fs::read(&path).map_err(|why| format!("Unable to read {}: {}", path.display(), why))?;
+
+        serde_json::from_slice(&contents)
+            .map_err(|why| format!("Unable to parse {}: {}", path.display(), why))
+    }
+
+    pub fn save(&self, snapshot_dir: &Path) -> Result<(), String> {
+        let path = snapshot_dir.join(SNAPSHOT_FILE);
+        let contents = serde_json::to_vec_pretty(self)
+            .map_err(|why| format!("Unable to serialise snapshot metadata: {}", why))?;
+
+        host::write_atomic(&path, &contents)
+            .map_err(|why| format!("Unable to write {}: {}", path.display(), why))
+    }
+}
+
+/// Erases the LUKS header and keyfile of a snapshot, so that it can never be
+/// decrypted, and then removes it. The whole image is overwritten too if
+/// `shred` is set.
+pub fn erase_snapshot(snapshot_dir: &Path, shred: bool) -> DriverResult<()> {
+    let image = snapshot_dir.join("volume.img");
+    let header = snapshot_dir.join(HEADER_FILE);
+    let key_file = snapshot_dir.join("keyfile");
+
+    if header.exists() {
+        erase::erase_header(&header).map_err(DriverError::Crypto)?;
+    } else if image.exists() {
+        erase::erase_header(&image).map_err(DriverError::Crypto)?;
+    }
+    if shred && image.exists() {
+        erase::shred(&image).map_err(DriverError::Io)?;
+    }
+    if key_file.exists() {
+        erase::shred_and_remove(&key_file).map_err(DriverError::Io)?;
+    }
+
+    fs::remove_dir_all(snapshot_dir).map_err(|why| {
+        DriverError::io(
+            format!("Unable to remove snapshot {}", snapshot_dir.display()),
+            why,
+        )
+    })
+}
+
+/// Where a restore keeps one of the volume's files until the snapshot's has
+/// taken its place.
+fn previous(path: &Path) -> PathBuf {
+    path.with_extension(PREVIOUS_EXTENSION)
+}
+
+fn move_file(from: &Path, to: &Path) -> DriverResult<()> {
+    fs::rename(from, to).map_err(|why| {
+        DriverError::io(
+            format!("Unable to move {} to {}", from.display(), to.display()),
+            why,
+        )
+    })
+}
+
+fn remove_if_exists(path: &Path) -> DriverResult<()> {
+    match fs::remove_file(path) {
+        Err(ref why) if why.kind() == io::ErrorKind::NotFound => Ok(()),
+        removed => removed.map_err(|why| DriverError::Io(why.to_string())),
+    }
+}
+
+/// Erases the header and keyfile that a restore replaced, which unlock
+/// nothing that is still kept.
+fn discard_previous(key_file: &Path, header: Option<&Path>) -> DriverResult<()> {
+    if let Some(previous_header) = header.map(previous).filter(|path| path.exists()) {
+        erase::erase_header(&previous_header).map_err(DriverError::Crypto)?;
+        erase::shred_and_remove(&previous_header).map_err(DriverError::Io)?;
+    }
+    let previous_key_file = previous(key_file);
+    if previous_key_file.exists() {
+        erase::shred_and_remove(&previous_key_file).map_err(DriverError::Io)?;
+    }
+
+    Ok(())
+}
+
+/// Finishes a restore of the volume in `volume_dir` that was interrupted by a
+/// crash. Until the snapshot's image has been moved into place the restore is
+/// undone, putting back the header and keyfile it set aside, and after that
+/// those are erased.
+pub fn recover_restore(volume_dir: &Path, header: Option<&Path>) -> DriverResult<()> {
+    let restoring_img = volume_dir
+        .join("volume.img")
+        .with_extension(RESTORING_EXTENSION);
+    let key_file = volume_dir.join("keyfile");
+    if !restoring_img.exists() {
+        return discard_previous(&key_file, header);
+    }
+
+    warn!("Undoing an incomplete restore of {}", volume_dir.display());
+    let mut files = vec![key_file.as_path()];
+    files.extend(header);
+    for file in files {
+        if previous(file).exists() {
+            move_file(&previous(file), file)?;
+        }
+        remove_if_exists(&file.with_extension(RESTORING_EXTENSION))?;
+    }
+
+    remove_if_exists(&restoring_img)
+}
+
+impl LuksVolumeDriver {
+    fn snapshot_dir(&self, name: &str, snapshot: &str) -> DriverResult<PathBuf> {
+        validate_volume_name(name)?;
+        validate_volume_name(snapshot)?;
+
+        Ok(self.data_dir.join(name).join(SNAPSHOT_DIR).join(snapshot))
+    }
+
+    /// Takes a point-in-time copy of a volume: its image, its detached header
+    /// if it has one, and a keyfile of its own wrapped by the HSM. A mounted
+    /// volume's filesystem is frozen while it is copied, so the snapshot is
+    /// consistent. The data never leaves its encrypted form. The volume is
+    /// claimed as busy while it is copied.
+    pub(super) fn create_snapshot(&self, name: &str, snapshot: &str) -> DriverResult<()> {
+        let snapshot_dir = &self.snapshot_dir(name, snapshot)?;
+        let _claim = self.busy.claim(name)?;
+        let volume_dir = &self.data_dir.join(name);
+        let volume_img = &volume_dir.join("volume.img");
+        let size = fs::metadata(&volume_img)
+            .map_err(|why| DriverError::io(format!("No volume named {}", name), why))?
+            .len();
+        if snapshot_dir.exists() {
+            return Err(DriverError::AlreadyExists(format!(
+                "Volume {} already has a snapshot named {}",
+                name, snapshot
+            )));
+        }
+        let metadata = self.volume_metadata(name)?;
+        let header = &metadata.luks.header_path(&volume_dir);
+        let overcommit =
+            VolumeOptions::from_opts(metadata.options).map_or(false, |options| options.overcommit);
+        let snapshots_dir = &volume_dir.join(SNAPSHOT_DIR);
+        fs::create_dir_all(&snapshots_dir).map_err(|why| {
+            DriverError::io(format!("Unable to create {}", snapshots_dir.display()), why)
+        })?;
+        self.check_free_space(name, size, overcommit)?;
+        let staging_dir = &snapshots_dir.join(format!(".{}{}", snapshot, STAGING_SUFFIX));
+
+        let mountpoint = self.mountpoint(name);
+        let mut secret_key = self.get_luks_key(name)?;
+
+        let mut rollback = Rollback::new();
+        let mut do_steps = || -> DriverResult<()> {
+            fs::create_dir(&staging_dir).map_err(|why| {
+                let message = format!(
+                    "Unable to create the snapshot directory {}: {}",
+                    &staging_dir.display(),
+                    why
+                );
+                match why.kind() {
+                    io::ErrorKind::AlreadyExists => DriverError::Busy(message),
+                    _ => DriverError::Io(message),
+                }
+            })?;
+            rollback.push("create the snapshot directory", move || {
+                fs::remove_dir_all(&staging_dir).map_err(|why| DriverError::Io(why.to_string()))
+            });
+
+            let snapshot_img = staging_dir.join("volume.img");
+            let snapshot_header = staging_dir.join(HEADER_FILE);
+            let mut files = vec![(volume_img.as_path(), snapshot_img.as_path())];
+            if let Some(header) = header {
+                files.push((header.as_path(), snapshot_header.as_path()));
+            }
+            self.copy_volume_files(name, mountpoint.as_deref(), &files)?;
+
+            self.store_luks_key(&staging_dir, &secret_key)?;
+            SnapshotMetadata {
+                created_at: Utc::now().to_rfc3339(),
+                size,
+                detached_header: header.is_some(),
+            }
+            .save(&staging_dir)
+            .map_err(DriverError::Io)?;
+
+            fs::rename(&staging_dir, &snapshot_dir).map_err(|why| {
+                DriverError::Io(format!(
+                    "Unable to move {} into place: {}",
+                    &snapshot_dir.display(),
+                    why
+                ))
+            })?;
+            host::sync_dir(&snapshots_dir).map_err(|why| {
+                DriverError::Io(format!(
+                    "Unable to sync {}: {}",
+                    &snapshots_dir.display(),
+                    why
+                ))
+            })
+        };
+
+        let result = do_steps();
+        crypto::wipe(&mut secret_key);
+        match result {
+            Ok(()) => {
+                rollback.commit();
+                Ok(())
+            }
+            Err(why) => {
+                rollback.rollback();
+                Err(why.context(format!(
+                    "Unable to snapshot volume {} as {}",
+                    name, snapshot
+                )))
+            }
+        }
+    }
+
+    /// The snapshots of a volume, oldest first.
+    pub(super) fn list_snapshots(&self, name: &str) -> DriverResult<Vec<Snapshot>> {
+        validate_volume_name(name)?;
+        let volume_dir = self.data_dir.join(name);
+        if !volume_dir.exists() {
+            return Err(DriverError::NotFound(format!("No volume named {}", name)));
+        }
+
+        let snapshots_dir = volume_dir.join(SNAPSHOT_DIR);
+        let entries = match fs::read_dir(&snapshots_dir) {
+            Ok(entries) => entries,
+            Err(ref why) if why.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(why) => {
+                return Err(DriverError::io(
+                    format!("Unable to read {}", snapshots_dir.display()),
+                    why,
+                ))
+            }
+        };
+
+        let mut snapshots: Vec<Snapshot> = entries
+            .filter_map(Result::ok)
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .filter(|snapshot| validate_volume_name(snapshot).is_ok())
+            .map(
+                |snapshot| match SnapshotMetadata::load(&snapshots_dir.join(&snapshot)) {
+                    Ok(metadata) => Snapshot {
+                        name: snapshot,
+                        created_at: Some(metadata.created_at),
+                        size: metadata.size,
+                    },
+                    Err(why) => {
+                        warn!("Unable to describe snapshot {}: {}", snapshot, why);
+                        Snapshot {
+                            name: snapshot,
+                            created_at: None,
+                            size: 0,
+                        }
+                    }
+                },
+            )
+            .collect();
+        snapshots.sort_by(|a, b| (&a.created_at, &a.name).cmp(&(&b.created_at, &b.name)));
+
+        Ok(snapshots)
+    }
+
+    /// Puts a volume back as it was when a snapshot was taken. The volume
+    /// must not be in use, and is claimed as busy while the snapshot is copied
+    /// in. The snapshot is kept, so it can be restored again.
+    pub(super) fn restore_snapshot(&self, name: &str, snapshot: &str) -> DriverResult<()> {
+        let snapshot_dir = &self.snapshot_dir(name, snapshot)?;
+        let _claim = self.busy.claim(name)?;
+        if !snapshot_dir.exists() {
+            return Err(DriverError::NotFound(format!(
+                "Volume {} has no snapshot named {}",
+                name, snapshot
+            )));
+        }
+        let snapshot_metadata = SnapshotMetadata::load(&snapshot_dir).map_err(DriverError::Io)?;
+        let volume_dir = &self.data_dir.join(name);
+        let volume_img = &volume_dir.join("volume.img");
+        let metadata = self.volume_metadata(name)?;
+        let header = &metadata.luks.header_path(&volume_dir);
+        if snapshot_metadata.detached_header != header.is_some() {
+            return Err(DriverError::Crypto(format!(
+                "Snapshot {} doesn't match where the LUKS header of volume {} is kept",
+                snapshot, name
+            )));
+        }
+        let preallocate =
+            VolumeOptions::from_opts(metadata.options).map_or(false, |options| options.preallocate);
+
+        let mapper_name = Self::mapper_name(name)?;
+        let is_mapped = host::active_mappers()
+            .map_err(DriverError::DeviceMapper)?
+            .contains(&mapper_name);
+        if self.mountpoint(name).is_some() || is_mapped {
+            return Err(DriverError::Busy(format!(
+                "Volume {} must be unmounted before a snapshot can be restored",
+                name
+            )));
+        }
+        let mut secret_key = self.read_luks_key(&snapshot_dir)?;
+
+        // The snapshot is copied in beside the volume's files first, and the
+        // volume's own header and keyfile are set aside rather than replaced,
+        // so that a failure part way through leaves the volume as it was.
+        let restoring_img = &volume_img.with_extension(RESTORING_EXTENSION);
+        let restoring_header = &header
+            .as_ref()
+            .map(|header| header.with_extension(RESTORING_EXTENSION));
+        let key_file = &volume_dir.join("keyfile");
+        let previous_key_file = &previous(key_file);
+        let previous_header = &header.as_ref().map(|header| previous(header));
+        let mut rollback = Rollback::new();
+        let mut do_steps = || -> DriverResult<()> {
+            rollback.push("copy the snapshot image", move || {
+                remove_if_exists(&restoring_img)
+            });
+            host::copy_file(&snapshot_dir.join("volume.img"), &restoring_img)
+                .map_err(|why| DriverError::io("Unable to copy the snapshot image", why))?;
+            if let Some(restoring_header) = restoring_header {
+                rollback.push("copy the snapshot header", move || {
+                    remove_if_exists(&restoring_header)
+                });
+                host::copy_file(&snapshot_dir.join(HEADER_FILE), &restoring_header)
+                    .map_err(|why| DriverError::io("Unable to copy the snapshot header", why))?;
+            }
+            if preallocate {
+                self.preallocate_image(&restoring_img, snapshot_metadata.size)?;
+            }
+
+            move_file(&key_file, &previous_key_file)?;
+            rollback.push("set the keyfile aside", move || {
+                move_file(&previous_key_file, &key_file)
+            });
+            self.store_luks_key(&volume_dir, &secret_key)?;
+            if let (Some(header), Some(restoring_header), Some(previous_header)) =
+                (header, restoring_header, previous_header)
+            {
+                move_file(&header, &previous_header)?;
+                rollback.push("set the LUKS header aside", move || {
+                    move_file(&previous_header, &header)
+                });
+                move_file(&restoring_header, &header)?;
+            }
+
+            // Swapping the image in is what commits the restore, as far as
+            // recover_restore is concerned.
+            move_file(&restoring_img, &volume_img)
+        };
+
+        let result = do_steps();
+        crypto::wipe(&mut secret_key);
+        if let Err(why) = result {
+            rollback.rollback();
+            return Err(why.context(format!(
+                "Unable to restore volume {} from snapshot {}",
+                name, snapshot
+            )));
+        }
+        rollback.commit();
+
+        let mut dirs = vec![volume_dir.as_path()];
+        dirs.extend(header.as_ref().and_then(|header| header.parent()));
+        for dir in dirs {
+            host::sync_dir(dir)
+                .map_err(|why| DriverError::io(format!("Unable to sync {}", dir.display()), why))?;
+        }
+        if let Err(why) = discard_previous(&key_file, header.as_deref()) {
+            warn!(
+                "Unable to erase the replaced files of volume {}: {}",
+                name, why
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Removes a snapshot so that it can't be recovered, the same way as a
+    /// volume is removed.
+    pub(super) fn remove_snapshot(&self, name: &str, snapshot: &str) -> DriverResult<()> {
+        let snapshot_dir = self.snapshot_dir(name, snapshot)?;
+        if !snapshot_dir.exists() {
+            return Err(DriverError::NotFound(format!(
+                "Volume {} has no snapshot named {}",
+                name, snapshot
+            )));
+        }
+        let shred = self
+            .volume_metadata(name)
+            .map(|metadata| metadata.shred)
+            .unwrap_or(false);
+
+        let _claim = self.busy.claim(name)?;
+        erase_snapshot(&snapshot_dir, shred).map_err(|why| {
+            why.context(format!(
+                "Unable to remove snapshot {} of volume {}",
+                snapshot, name
+            ))
+        })
+    }
+}
+
+#[test]
+fn test_snapshot_metadata_round_trip() {
+    let metadata = SnapshotMetadata {
+        created_at: "2019-12-01T00:00:00+00:00".to_string(),
+        size: 1 << 30,
+        detached_header: true,
+    };
+
+    let json = serde_json::to_string(&metadata).unwrap();
+    let parsed: SnapshotMetadata = serde_json::from_str(&json).unwrap();
+    assert_eq!(parsed.size, 1 << 30);
+    assert!(parsed.detached_header);
+
+    let legacy: SnapshotMetadata =
+        serde_json::from_str(r#"{"created_at": "2019-12-01T00:00:00+00:00", "size": 512}"#)
+            .unwrap();
+    assert!(!legacy.detached_header);
+}
+
+#[test]
+fn test_recover_interrupted_restore() {
+    let volume_dir = std::env::temp_dir().join(format!("luks-restore-{}", std::process::id()));
+    fs::create_dir_all(&volume_dir).unwrap();
+    fs::write(volume_dir.join("volume.img"), "volume").unwrap();
+    fs::write(volume_dir.join("volume.restoring"), "snapshot").unwrap();
+    fs::write(volume_dir.join("keyfile"), "snapshot key").unwrap();
+    fs::write(volume_dir.join("keyfile.previous"), "volume key").unwrap();
+
+    recover_restore(&volume_dir, None).unwrap();
+    assert_eq!(fs::read(volume_dir.join("keyfile")).unwrap(), b"volume key");
+    assert_eq!(fs::read(volume_dir.join("volume.img")).unwrap(), b"volume");
+    assert!(!volume_dir.join("keyfile.previous").exists());
+    assert!(!volume_dir.join("volume.restoring").exists());
+
+    fs::remove_dir_all(&volume_dir).unwrap();
+}
@@ -0,0 +1,67 @@
+use super::host;
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A volume that is mounted on the host, along with the IDs holding it.
+#[derive(Serialize, Deserialize)]
+pub struct MountState {
+    pub mapper_name: String,
+    pub mountpoint: String,
+    pub ids: HashSet<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct DriverState {
+    #[serde(default)]
+    pub volumes: HashMap<String, MountState>,
+}
+
+/// Keeps the driver state in a JSON file so that live mappings and mounts
+/// survive a restart of the plugin.
+pub struct StateStore {
+    path: PathBuf,
+}
+
+impl StateStore {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+        }
+    }
+
+    pub fn load(&self) -> Result<DriverState, String> {
+        match fs::read(&self.path) {
+            Ok(contents) => serde_json::from_slice(&contents).map_err(|why| {
+                format!(
+                    "Unable to parse state file {}: {}",
+                    self.path.display(),
+                    why
+                )
+            }),
+            Err(ref why) if why.kind() == io::ErrorKind::NotFound => Ok(DriverState::default()),
+            Err(why) => Err(format!(
+                "Unable to read state file {}: {}",
+                self.path.display(),
+                why
+            )),
+        }
+    }
+
+    pub fn save(&self, state: &DriverState) -> Result<(), String> {
+        let contents = serde_json::to_vec_pretty(state)
+            .map_err(|why| format!("Unable to serialise driver state: {}", why))?;
+
+        host::write_atomic(&self.path, &contents).map_err(|why| {
+            format!(
+                "Unable to write state file {}: {}",
+                self.path.display(),
+                why
+            )
+        })
+    }
+}
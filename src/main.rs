@@ -1,12 +1,13 @@
 extern crate actix_http;
 extern crate actix_web;
 extern crate base64;
-extern crate block_utils;
+extern crate chrono;
 extern crate clap;
 extern crate cryptsetup_rs;
 extern crate derive_more;
 extern crate futures;
 extern crate lazy_static;
+extern crate libc;
 extern crate log;
 extern crate openssl;
 extern crate serde;
@@ -21,10 +22,74 @@ mod hsm;
 mod luks;
 mod plugin;
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use config_json::ConfigJson;
+use std::collections::HashMap;
 use std::path::Path;
+use std::process;
 use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// A `snapshot` subcommand that takes a volume and one of its snapshots.
+fn snapshot_command<'a, 'b>(name: &'a str, about: &'b str) -> App<'a, 'b> {
+    SubCommand::with_name(name)
+        .about(about)
+        .arg(
+            Arg::with_name("name")
+                .value_name("NAME")
+                .help("The volume.")
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("snapshot")
+                .value_name("SNAPSHOT")
+                .help("The name of the snapshot.")
+                .required(true),
+        )
+}
+
+/// Sends a `snapshot` subcommand to the admin socket, printing the snapshots
+/// for `list`.
+fn snapshot_request(socket: &Path, command: (&str, Option<&ArgMatches>)) -> Result<(), String> {
+    let args = match command {
+        (_, Some(args)) => args,
+        _ => return Ok(()),
+    };
+    let name = args.value_of("name").unwrap_or_default().to_string();
+    let snapshot = args.value_of("snapshot").unwrap_or_default().to_string();
+    let endpoint = match command.0 {
+        "create" => "Admin.CreateSnapshot",
+        "restore" => "Admin.RestoreSnapshot",
+        "delete" => "Admin.RemoveSnapshot",
+        "list" => {
+            let body = plugin::admin::send_request(
+                socket,
+                "Admin.ListSnapshots",
+                &plugin::admin::ListSnapshotsRequest { name },
+            )?;
+            let response: plugin::admin::ListSnapshotsResponse = serde_json::from_str(&body)
+                .map_err(|why| format!("Malformed response from the driver: {}", why))?;
+            for snapshot in response.snapshots {
+                println!(
+                    "{}\t{}\t{}",
+                    snapshot.name,
+                    snapshot.created_at.unwrap_or_else(|| "-".to_string()),
+                    snapshot.size
+                );
+            }
+            return Ok(());
+        }
+        _ => return Ok(()),
+    };
+
+    plugin::admin::send_request(
+        socket,
+        endpoint,
+        &plugin::admin::SnapshotRequest { name, snapshot },
+    )
+    .map(|_| ())
+}
 
 fn main() {
     simple_logger::init_with_level(log::Level::Info).expect("Unable to initialise the logger");
@@ -33,6 +98,7 @@ fn main() {
         .version("0.1")
         .author("Rich B. <richbayliss@gmail.com>")
         .about("Provides a Docker volume plugin for LUKS encrypted volumes.")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("unix_socket")
                 .short("s")
@@ -42,6 +108,15 @@ fn main() {
                 .default_value("/run/docker/plugins/luks.sock")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("admin_socket")
+                .short("a")
+                .long("admin-socket")
+                .value_name("FILE")
+                .help("The unix socket location to listen on for admin requests.")
+                .default_value("/run/luks-volume-driver/admin.sock")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("data_dir")
                 .short("d")
@@ -79,8 +154,151 @@ fn main() {
                 .default_value("v1")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("default_opt")
+                .short("o")
+                .long("default-opt")
+                .value_name("KEY=VALUE")
+                .help("A default volume option, such as cipher=aes-xts-plain64 or key_size=512.")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+                .validator(|value| {
+                    if value.contains('=') {
+                        Ok(())
+                    } else {
+                        Err("The option must be in the form KEY=VALUE".to_string())
+                    }
+                }),
+        )
+        .arg(
+            Arg::with_name("mandatory_mount_opts")
+                .long("mandatory-mount-opts")
+                .value_name("OPTS")
+                .help("Mount options every volume is mounted with, such as nodev,nosuid.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("fstrim_interval")
+                .long("fstrim-interval")
+                .value_name("SECONDS")
+                .help("How often to trim mounted volumes that allow discards, such as with discard=periodic.")
+                .takes_value(true)
+                .validator(|value| match value.parse::<u64>() {
+                    Ok(0) => Err("The interval must be at least one second".to_string()),
+                    Ok(_) => Ok(()),
+                    Err(why) => Err(why.to_string()),
+                }),
+        )
+        .arg(
+            Arg::with_name("header_dir")
+                .long("header-dir")
+                .value_name("DIR")
+                .help("The directory to keep detached LUKS headers in, instead of beside each volume.")
+                .takes_value(true),
+        )
+        .subcommand(
+            SubCommand::with_name("resize")
+                .about("Resizes a volume of the running driver, growing its filesystem to match.")
+                .arg(
+                    Arg::with_name("name")
+                        .value_name("NAME")
+                        .help("The volume to resize.")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("size")
+                        .value_name("SIZE")
+                        .help("The new size of the volume, such as 20G.")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("remove")
+                .about("Removes a volume of the running driver, erasing its LUKS header and key.")
+                .arg(
+                    Arg::with_name("name")
+                        .value_name("NAME")
+                        .help("The volume to remove.")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("shred")
+                        .long("shred")
+                        .help("Overwrite the whole volume image before deleting it."),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Unmount and close the volume first if it is in use."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("snapshot")
+                .about("Manages the snapshots of a volume of the running driver.")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(snapshot_command("create", "Takes a snapshot of a volume."))
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("Lists the snapshots of a volume, oldest first.")
+                        .arg(
+                            Arg::with_name("name")
+                                .value_name("NAME")
+                                .help("The volume.")
+                                .required(true),
+                        ),
+                )
+                .subcommand(snapshot_command(
+                    "restore",
+                    "Restores an unmounted volume from one of its snapshots.",
+                ))
+                .subcommand(snapshot_command("delete", "Deletes a snapshot of a volume.")),
+        )
         .get_matches();
 
+    let admin_socket = args
+        .value_of("admin_socket")
+        .expect("A value for --admin-socket must be provided")
+        .to_string();
+
+    let admin_request = match args.subcommand() {
+        ("resize", Some(resize_args)) => Some(
+            plugin::admin::send_request(
+                Path::new(&admin_socket),
+                "Admin.Resize",
+                &plugin::admin::ResizeVolumeRequest {
+                    name: resize_args.value_of("name").unwrap_or_default().to_string(),
+                    size: resize_args.value_of("size").unwrap_or_default().to_string(),
+                },
+            )
+            .map(|_| ()),
+        ),
+        ("remove", Some(remove_args)) => Some(
+            plugin::admin::send_request(
+                Path::new(&admin_socket),
+                "Admin.Remove",
+                &plugin::admin::RemoveVolumeRequest {
+                    name: remove_args.value_of("name").unwrap_or_default().to_string(),
+                    shred: remove_args.is_present("shred"),
+                    force: remove_args.is_present("force"),
+                },
+            )
+            .map(|_| ()),
+        ),
+        ("snapshot", Some(snapshot_args)) => Some(snapshot_request(
+            Path::new(&admin_socket),
+            snapshot_args.subcommand(),
+        )),
+        _ => None,
+    };
+    if let Some(result) = admin_request {
+        if let Err(err) = result {
+            eprintln!("error: {}", err);
+            process::exit(1);
+        }
+        return;
+    }
+
     let config_json_path = &args
         .value_of("config_json")
         .expect("A value for --config-json must be provided")
@@ -90,6 +308,23 @@ fn main() {
         .expect("A value for --api-version must be provided")
         .to_string();
 
+    let default_opts: HashMap<String, String> = args
+        .values_of("default_opt")
+        .map(|values| {
+            values
+                .map(|opt| {
+                    let mut kv = opt.splitn(2, '=');
+                    let key = kv.next().unwrap_or_default().to_string();
+                    let value = kv
+                        .next()
+                        .expect("A value for --default-opt must be in the form KEY=VALUE")
+                        .to_string();
+                    (key, value)
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
     let config =
         ConfigJson::from_file(Path::new(&config_json_path)).expect("Unable to read config.json");
 
@@ -106,14 +341,47 @@ fn main() {
             .expect("A value for the --mount-dir must be provided")
             .to_string(),
         Some(Box::new(hsm)),
-    );
+    )
+    .with_default_opts(default_opts)
+    .expect("Unable to apply the default volume options")
+    .with_mandatory_mount_opts(args.value_of("mandatory_mount_opts").unwrap_or_default())
+    .expect("Unable to apply the mandatory mount options");
+    let driver = match args.value_of("header_dir") {
+        Some(dir) => driver
+            .with_header_dir(dir)
+            .expect("Unable to use the header dir"),
+        None => driver,
+    };
 
     let listen_socket = args
         .value_of("unix_socket")
         .expect("A value for --unix-socket must be provided");
 
+    let driver = Arc::new(driver);
+
+    if let Some(interval) = args.value_of("fstrim_interval") {
+        let interval = Duration::from_secs(
+            interval
+                .parse()
+                .expect("A value for --fstrim-interval must be a number of seconds"),
+        );
+        let driver = Arc::clone(&driver);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            driver.trim_volumes();
+        });
+    }
+
+    let admin: plugin::admin::AdminPlugin<luks::LuksVolumeDriver> =
+        plugin::admin::AdminPlugin::new(Path::new(&admin_socket), Arc::clone(&driver));
+    thread::spawn(move || {
+        if let Err(err) = admin.start() {
+            eprintln!("error starting admin host: {}", err)
+        }
+    });
+
     let host: plugin::VolumePlugin<luks::LuksVolumeDriver> =
-        plugin::VolumePlugin::new(Path::new(&listen_socket), Arc::new(driver));
+        plugin::VolumePlugin::new(Path::new(&listen_socket), driver);
 
     if let Err(err) = host.start() {
         eprintln!("error starting plugin host: {}", err)
@@ -0,0 +1,350 @@
+use crate::plugin::error::DriverResult;
+use crate::plugin::rpc_request::RpcRequest;
+use crate::plugin::{error_response, RpcError};
+use actix_web::{middleware, web, App, HttpRequest, HttpResponse, HttpServer};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Operations that Docker's volume plugin protocol has no room for, offered
+/// to the host's administrators only.
+pub trait AdminDriver: Send + Sync {
+    /// Resizes a volume to `size`, given in the same form as the `size`
+    /// volume option.
+    fn resize(&self, name: String, size: String) -> DriverResult<()>;
+    /// Removes a volume, overwriting the whole of its image if `shred` is
+    /// set. A volume that is in use is unmounted and closed first if `force`
+    /// is set.
+    fn remove(&self, name: String, shred: bool, force: bool) -> DriverResult<()>;
+    /// Takes a point-in-time copy of a volume, named `snapshot`.
+    fn create_snapshot(&self, name: String, snapshot: String) -> DriverResult<()>;
+    /// The snapshots of a volume, oldest first.
+    fn list_snapshots(&self, name: String) -> DriverResult<Vec<Snapshot>>;
+    /// Puts a volume, which must not be in use, back as it was when
+    /// `snapshot` was taken.
+    fn restore_snapshot(&self, name: String, snapshot: String) -> DriverResult<()>;
+    /// Deletes a snapshot, erasing its LUKS header and keyfile first.
+    fn remove_snapshot(&self, name: String, snapshot: String) -> DriverResult<()>;
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct Snapshot {
+    #[serde(rename = "Name")]
+    pub name: String,
+
+    /// When the snapshot was taken, in RFC 3339 format.
+    #[serde(rename = "CreatedAt")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+
+    /// The size of the volume when the snapshot was taken, in bytes.
+    #[serde(rename = "Size")]
+    pub size: u64,
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct ResizeVolumeRequest {
+    #[serde(rename = "Name")]
+    pub name: String,
+    #[serde(rename = "Size")]
+    pub size: String,
+}
+
+impl Default for ResizeVolumeRequest {
+    fn default() -> Self {
+        Self {
+            name: String::default(),
+            size: String::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct RemoveVolumeRequest {
+    #[serde(rename = "Name")]
+    pub name: String,
+    #[serde(rename = "Shred")]
+    #[serde(default)]
+    pub shred: bool,
+    #[serde(rename = "Force")]
+    #[serde(default)]
+    pub force: bool,
+}
+
+impl Default for RemoveVolumeRequest {
+    fn default() -> Self {
+        Self {
+            name: String::default(),
+            shred: false,
+            force: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct SnapshotRequest {
+    #[serde(rename = "Name")]
+    pub name: String,
+    #[serde(rename = "Snapshot")]
+    pub snapshot: String,
+}
+
+impl Default for SnapshotRequest {
+    fn default() -> Self {
+        Self {
+            name: String::default(),
+            snapshot: String::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct ListSnapshotsRequest {
+    #[serde(rename = "Name")]
+    pub name: String,
+}
+
+impl Default for ListSnapshotsRequest {
+    fn default() -> Self {
+        Self {
+            name: String::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct ListSnapshotsResponse {
+    #[serde(rename = "Snapshots")]
+    pub snapshots: Vec<Snapshot>,
+    #[serde(rename = "Err")]
+    pub err: String,
+}
+
+/// Serves the admin endpoints on their own unix socket, which only root can
+/// connect to, so they are never reachable through the Docker plugin socket.
+pub struct AdminPlugin<T> {
+    socket_path: PathBuf,
+    driver: Arc<T>,
+}
+
+impl<T> AdminPlugin<T>
+where
+    T: AdminDriver + 'static,
+{
+    pub fn new(socket: &Path, driver: Arc<T>) -> Self {
+        Self {
+            socket_path: socket.to_path_buf(),
+            driver,
+        }
+    }
+
+    pub fn start(&self) -> io::Result<()> {
+        if let Err(err) = fs::remove_file(&self.socket_path) {
+            if err.kind() != io::ErrorKind::NotFound {
+                return Err(err);
+            }
+        }
+        let dir = self.socket_path.parent().unwrap_or_else(|| Path::new("/"));
+        fs::create_dir_all(dir)?;
+        info!(
+            "Listening for admin requests on unix://{path}",
+            path = self.socket_path.to_str().unwrap()
+        );
+
+        // The socket is bound inside a directory that only root can enter and
+        // moved into place once its mode is set, so nobody else can ever
+        // connect to it.
+        let private_dir = dir.join(format!(
+            ".{}.bind",
+            self.socket_path
+                .file_name()
+                .map(|name| name.to_string_lossy())
+                .unwrap_or_default()
+        ));
+        if let Err(err) = fs::remove_dir_all(&private_dir) {
+            if err.kind() != io::ErrorKind::NotFound {
+                return Err(err);
+            }
+        }
+        fs::DirBuilder::new().mode(0o700).create(&private_dir)?;
+        let bind_path = private_dir.join("admin.sock");
+        let driver = Arc::clone(&self.driver);
+        let bound = HttpServer::new(move || {
+            App::new()
+                .data(driver.clone())
+                .wrap(middleware::Logger::default())
+                .service(web::resource("/Admin.Resize").route(web::post().to(
+                    move |resize_request: RpcRequest<ResizeVolumeRequest>,
+                          req: HttpRequest|
+                          -> HttpResponse {
+                        Self::handle_volume_resize(
+                            resize_request.0,
+                            req.app_data::<Arc<T>>().expect("No driver found").clone(),
+                        )
+                    },
+                )))
+                .service(web::resource("/Admin.Remove").route(web::post().to(
+                    move |remove_request: RpcRequest<RemoveVolumeRequest>,
+                          req: HttpRequest|
+                          -> HttpResponse {
+                        Self::handle_volume_remove(
+                            remove_request.0,
+                            req.app_data::<Arc<T>>().expect("No driver found").clone(),
+                        )
+                    },
+                )))
+                .service(web::resource("/Admin.CreateSnapshot").route(web::post().to(
+                    move |snapshot_request: RpcRequest<SnapshotRequest>,
+                          req: HttpRequest|
+                          -> HttpResponse {
+                        Self::handle_snapshot_create(
+                            snapshot_request.0,
+                            req.app_data::<Arc<T>>().expect("No driver found").clone(),
+                        )
+                    },
+                )))
+                .service(web::resource("/Admin.ListSnapshots").route(web::post().to(
+                    move |list_request: RpcRequest<ListSnapshotsRequest>,
+                          req: HttpRequest|
+                          -> HttpResponse {
+                        Self::handle_snapshot_list(
+                            list_request.0,
+                            req.app_data::<Arc<T>>().expect("No driver found").clone(),
+                        )
+                    },
+                )))
+                .service(
+                    web::resource("/Admin.RestoreSnapshot").route(web::post().to(
+                        move |snapshot_request: RpcRequest<SnapshotRequest>,
+                              req: HttpRequest|
+                              -> HttpResponse {
+                            Self::handle_snapshot_restore(
+                                snapshot_request.0,
+                                req.app_data::<Arc<T>>().expect("No driver found").clone(),
+                            )
+                        },
+                    )),
+                )
+                .service(web::resource("/Admin.RemoveSnapshot").route(web::post().to(
+                    move |snapshot_request: RpcRequest<SnapshotRequest>,
+                          req: HttpRequest|
+                          -> HttpResponse {
+                        Self::handle_snapshot_remove(
+                            snapshot_request.0,
+                            req.app_data::<Arc<T>>().expect("No driver found").clone(),
+                        )
+                    },
+                )))
+        })
+        .bind_uds(&bind_path)
+        .and_then(|server| {
+            fs::set_permissions(&bind_path, fs::Permissions::from_mode(0o600))?;
+            fs::rename(&bind_path, &self.socket_path)?;
+            Ok(server)
+        });
+        let _ = fs::remove_dir_all(&private_dir);
+        let server = bound?;
+
+        server.run()
+    }
+
+    fn handle_volume_resize(resize_request: ResizeVolumeRequest, driver: Arc<T>) -> HttpResponse {
+        match T::resize(&driver, resize_request.name, resize_request.size) {
+            Ok(_) => HttpResponse::Ok().json(RpcError::default()),
+            Err(e) => error_response(e),
+        }
+    }
+
+    fn handle_volume_remove(remove_request: RemoveVolumeRequest, driver: Arc<T>) -> HttpResponse {
+        match T::remove(
+            &driver,
+            remove_request.name,
+            remove_request.shred,
+            remove_request.force,
+        ) {
+            Ok(_) => HttpResponse::Ok().json(RpcError::default()),
+            Err(e) => error_response(e),
+        }
+    }
+
+    fn handle_snapshot_create(snapshot_request: SnapshotRequest, driver: Arc<T>) -> HttpResponse {
+        match T::create_snapshot(&driver, snapshot_request.name, snapshot_request.snapshot) {
+            Ok(_) => HttpResponse::Ok().json(RpcError::default()),
+            Err(e) => error_response(e),
+        }
+    }
+
+    fn handle_snapshot_list(list_request: ListSnapshotsRequest, driver: Arc<T>) -> HttpResponse {
+        match T::list_snapshots(&driver, list_request.name) {
+            Ok(snapshots) => HttpResponse::Ok().json(ListSnapshotsResponse {
+                snapshots,
+                err: "".to_string(),
+            }),
+            Err(e) => error_response(e),
+        }
+    }
+
+    fn handle_snapshot_restore(snapshot_request: SnapshotRequest, driver: Arc<T>) -> HttpResponse {
+        match T::restore_snapshot(&driver, snapshot_request.name, snapshot_request.snapshot) {
+            Ok(_) => HttpResponse::Ok().json(RpcError::default()),
+            Err(e) => error_response(e),
+        }
+    }
+
+    fn handle_snapshot_remove(snapshot_request: SnapshotRequest, driver: Arc<T>) -> HttpResponse {
+        match T::remove_snapshot(&driver, snapshot_request.name, snapshot_request.snapshot) {
+            Ok(_) => HttpResponse::Ok().json(RpcError::default()),
+            Err(e) => error_response(e),
+        }
+    }
+}
+
+/// Sends a request to the admin socket of a running driver, for the admin
+/// CLI, returning the body of the response. A failure is reported with the
+/// error returned by the driver.
+pub fn send_request<R: Serialize>(
+    socket: &Path,
+    endpoint: &str,
+    request: &R,
+) -> Result<String, String> {
+    let body = serde_json::to_string(request)
+        .map_err(|why| format!("Unable to serialise the request: {}", why))?;
+    let mut stream = UnixStream::connect(socket)
+        .map_err(|why| format!("Unable to connect to {}: {}", socket.display(), why))?;
+
+    write!(
+        stream,
+        "POST /{} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        endpoint,
+        body.len(),
+        body
+    )
+    .map_err(|why| format!("Unable to send the request: {}", why))?;
+
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .map_err(|why| format!("Unable to read the response: {}", why))?;
+
+    let status = response
+        .split(' ')
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| "Malformed response from the driver".to_string())?;
+    let body = response.splitn(2, "\r\n\r\n").nth(1).unwrap_or_default();
+    if status == 200 {
+        return Ok(body.to_string());
+    }
+
+    let err = serde_json::from_str::<RpcError>(body)
+        .map(|rpc_error| rpc_error.err)
+        .unwrap_or_else(|_| format!("Request failed with status {}", status));
+
+    Err(err)
+}
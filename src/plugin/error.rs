@@ -0,0 +1,152 @@
+use crate::crypto::CryptoError;
+use crate::plugin::name::NameError;
+
+use actix_web::http::StatusCode;
+use derive_more::Display;
+use log::Level;
+use std::fmt;
+use std::io;
+
+/// Everything that can go wrong in a volume driver, by kind, so that the
+/// plugin can answer with a sensible status and log level and operators can
+/// tell an HSM outage from a broken keyfile or a busy volume.
+#[derive(Debug, Display)]
+pub enum DriverError {
+    #[display(fmt = "Not found: {}", _0)]
+    NotFound(String),
+    #[display(fmt = "Already exists: {}", _0)]
+    AlreadyExists(String),
+    #[display(fmt = "Busy: {}", _0)]
+    Busy(String),
+    #[display(fmt = "Not enough space: {}", _0)]
+    NoSpace(String),
+    #[display(fmt = "HSM unavailable: {}", _0)]
+    HsmUnavailable(String),
+    #[display(fmt = "HSM denied the request: {}", _0)]
+    HsmDenied(String),
+    #[display(fmt = "Crypto failure: {}", _0)]
+    Crypto(String),
+    #[display(fmt = "Device-mapper failure: {}", _0)]
+    DeviceMapper(String),
+    #[display(fmt = "Mount failure: {}", _0)]
+    Mount(String),
+    #[display(fmt = "Filesystem failure: {}", _0)]
+    Filesystem(String),
+    /// dm-integrity found data that doesn't match its tag, so the image has
+    /// been corrupted or tampered with.
+    #[display(fmt = "Integrity failure: {}", _0)]
+    Integrity(String),
+    #[display(fmt = "Invalid options: {}", _0)]
+    InvalidOptions(String),
+    #[display(fmt = "{}", _0)]
+    InvalidName(NameError),
+    #[display(fmt = "I/O failure: {}", _0)]
+    Io(String),
+}
+
+pub type DriverResult<T> = Result<T, DriverError>;
+
+impl DriverError {
+    /// Prefixes the message with some context while keeping the kind of error.
+    pub fn context<C: fmt::Display>(self, context: C) -> Self {
+        let wrap = |why: String| format!("{}: {}", context, why);
+        match self {
+            Self::NotFound(why) => Self::NotFound(wrap(why)),
+            Self::AlreadyExists(why) => Self::AlreadyExists(wrap(why)),
+            Self::Busy(why) => Self::Busy(wrap(why)),
+            Self::NoSpace(why) => Self::NoSpace(wrap(why)),
+            Self::HsmUnavailable(why) => Self::HsmUnavailable(wrap(why)),
+            Self::HsmDenied(why) => Self::HsmDenied(wrap(why)),
+            Self::Crypto(why) => Self::Crypto(wrap(why)),
+            Self::DeviceMapper(why) => Self::DeviceMapper(wrap(why)),
+            Self::Mount(why) => Self::Mount(wrap(why)),
+            Self::Filesystem(why) => Self::Filesystem(wrap(why)),
+            Self::Integrity(why) => Self::Integrity(wrap(why)),
+            Self::InvalidOptions(why) => Self::InvalidOptions(wrap(why)),
+            Self::InvalidName(why) => Self::InvalidName(why),
+            Self::Io(why) => Self::Io(wrap(why)),
+        }
+    }
+
+    /// Sorts an I/O error by its cause, so that running out of space or
+    /// finding something in the way isn't reported as a generic I/O failure.
+    pub fn io<C: fmt::Display>(context: C, error: io::Error) -> Self {
+        let why = format!("{}: {}", context, error);
+        match error.kind() {
+            io::ErrorKind::NotFound => Self::NotFound(why),
+            io::ErrorKind::AlreadyExists => Self::AlreadyExists(why),
+            _ if error.raw_os_error() == Some(libc::ENOSPC) => Self::NoSpace(why),
+            _ => Self::Io(why),
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::AlreadyExists(_) | Self::Busy(_) => StatusCode::CONFLICT,
+            Self::InvalidOptions(_) | Self::InvalidName(_) => StatusCode::BAD_REQUEST,
+            Self::NoSpace(_) => StatusCode::INSUFFICIENT_STORAGE,
+            Self::HsmUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::HsmDenied(_) => StatusCode::BAD_GATEWAY,
+            Self::Crypto(_)
+            | Self::DeviceMapper(_)
+            | Self::Mount(_)
+            | Self::Filesystem(_)
+            | Self::Integrity(_)
+            | Self::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Errors caused by the request are routine; errors from the host or the
+    /// HSM need an operator's attention.
+    pub fn log_level(&self) -> Level {
+        match self {
+            Self::NotFound(_) | Self::AlreadyExists(_) => Level::Info,
+            Self::Busy(_) | Self::NoSpace(_) | Self::InvalidOptions(_) | Self::InvalidName(_) => {
+                Level::Warn
+            }
+            _ => Level::Error,
+        }
+    }
+}
+
+impl From<NameError> for DriverError {
+    fn from(error: NameError) -> Self {
+        Self::InvalidName(error)
+    }
+}
+
+impl From<CryptoError> for DriverError {
+    fn from(error: CryptoError) -> Self {
+        match error {
+            CryptoError::Unavailable(why) => Self::HsmUnavailable(why),
+            CryptoError::Denied(why) => Self::HsmDenied(why),
+            error => Self::Crypto(error.to_string()),
+        }
+    }
+}
+
+#[test]
+fn test_io_error_kinds() {
+    let error = DriverError::io(
+        "Unable to create the disk image",
+        io::Error::from_raw_os_error(libc::ENOSPC),
+    );
+    assert_eq!(error.status_code(), StatusCode::INSUFFICIENT_STORAGE);
+    let error = DriverError::io(
+        "Unable to create the disk image",
+        io::Error::from_raw_os_error(libc::EROFS),
+    );
+    assert_eq!(error.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+}
+
+#[test]
+fn test_context_keeps_kind() {
+    let error = DriverError::from(CryptoError::Unavailable("timed out".to_string()))
+        .context("Unable to decrypt key file");
+    assert_eq!(error.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(
+        error.to_string(),
+        "HSM unavailable: Unable to decrypt key file: timed out"
+    );
+}
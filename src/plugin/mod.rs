@@ -1,9 +1,14 @@
+pub mod admin;
+pub mod error;
+pub mod name;
 pub mod rpc_request;
 pub mod volume;
 
+use crate::plugin::error::{DriverError, DriverResult};
+use crate::plugin::name::validate_volume_name;
 use crate::plugin::rpc_request::RpcRequest;
 use actix_web::{middleware, web, App, HttpRequest, HttpResponse, HttpServer};
-use log::info;
+use log::{info, log};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
@@ -39,6 +44,11 @@ impl RpcError {
     }
 }
 
+fn error_response(err: DriverError) -> RpcResponse {
+    log!(err.log_level(), "{}", err);
+    HttpResponse::build(err.status_code()).json(RpcError::from_str(&err.to_string()))
+}
+
 #[derive(Serialize, Deserialize, PartialEq)]
 pub enum Protocol {
     VolumeDriver,
@@ -59,13 +69,13 @@ impl Default for ActivateResponse {
 }
 
 pub trait VolumeDriver: Send + Sync {
-    fn create(&self, name: String, opts: Option<HashMap<String, String>>) -> Result<(), String>;
-    fn remove(&self, name: String) -> Result<(), String>;
-    fn mount(&self, name: String, id: String) -> Result<String, String>;
-    fn path(&self, name: String) -> Result<String, String>;
-    fn unmount(&self, name: String, id: String) -> Result<(), String>;
-    fn get(&self, name: String) -> Result<Volume, String>;
-    fn list(&self) -> Result<Vec<Volume>, String>;
+    fn create(&self, name: String, opts: Option<HashMap<String, String>>) -> DriverResult<()>;
+    fn remove(&self, name: String) -> DriverResult<()>;
+    fn mount(&self, name: String, id: String) -> DriverResult<String>;
+    fn path(&self, name: String) -> DriverResult<String>;
+    fn unmount(&self, name: String, id: String) -> DriverResult<()>;
+    fn get(&self, name: String) -> DriverResult<Volume>;
+    fn list(&self) -> DriverResult<Vec<Volume>>;
 }
 
 pub struct VolumePlugin<T> {
@@ -197,59 +207,77 @@ where
         HttpResponse::Ok().json(plugin_implements)
     }
 
+    /// Rejects names that don't follow Docker's volume name grammar before
+    /// they get anywhere near the driver or the filesystem.
+    fn check_volume_name(name: &str) -> Result<(), RpcResponse> {
+        validate_volume_name(name).map_err(|why| error_response(why.into()))
+    }
+
     fn handle_volume_create(
         create_request: volume::CreateVolumeRequest,
         driver: Arc<T>,
     ) -> RpcResponse {
+        if let Err(response) = Self::check_volume_name(&create_request.name) {
+            return response;
+        }
         match T::create(&driver, create_request.name, create_request.opts) {
             Ok(_) => HttpResponse::Ok().json(RpcError::default()),
-            Err(e) => HttpResponse::BadRequest().json(RpcError::from_str(&e)),
+            Err(e) => error_response(e),
         }
     }
 
     fn handle_volume_remove(name: String, driver: Arc<T>) -> RpcResponse {
+        if let Err(response) = Self::check_volume_name(&name) {
+            return response;
+        }
         match T::remove(&driver, name) {
             Ok(_) => HttpResponse::Ok().json(RpcError::default()),
-            Err(e) => HttpResponse::BadRequest().json(RpcError::from_str(&e)),
+            Err(e) => error_response(e),
         }
     }
     fn handle_volume_mount(name: String, id: String, driver: Arc<T>) -> RpcResponse {
+        if let Err(response) = Self::check_volume_name(&name) {
+            return response;
+        }
         match T::mount(&driver, String::from(&name), id) {
             Ok(mountpoint) => HttpResponse::Ok().json(volume::MountVolumeResponse {
                 mountpoint,
                 err: "".to_string(),
             }),
-            Err(e) => HttpResponse::BadRequest().json(RpcError::from_str(&e)),
+            Err(e) => error_response(e),
         }
     }
     fn handle_volume_path(name: String, driver: Arc<T>) -> RpcResponse {
+        if let Err(response) = Self::check_volume_name(&name) {
+            return response;
+        }
         match T::path(&driver, name) {
             Ok(mountpoint) => HttpResponse::Ok().json(volume::MountVolumeResponse {
                 mountpoint,
                 err: "".to_string(),
             }),
-            Err(e) => {
-                info!("{}", e);
-                HttpResponse::BadRequest().json(RpcError::from_str(&e))
-            }
+            Err(e) => error_response(e),
         }
     }
     fn handle_volume_unmount(name: String, id: String, driver: Arc<T>) -> RpcResponse {
+        if let Err(response) = Self::check_volume_name(&name) {
+            return response;
+        }
         match T::unmount(&driver, name, id) {
             Ok(_) => HttpResponse::Ok().json(RpcError::default()),
-            Err(e) => HttpResponse::BadRequest().json(RpcError::from_str(&e)),
+            Err(e) => error_response(e),
         }
     }
     fn handle_volume_get(name: String, driver: Arc<T>) -> RpcResponse {
+        if let Err(response) = Self::check_volume_name(&name) {
+            return response;
+        }
         match T::get(&driver, name) {
-            Ok(vol) => HttpResponse::Ok().json(volume::GetVolumeResponse {
-                volume: volume::Volume {
-                    name: vol.name,
-                    mountpoint: vol.mountpoint,
-                },
+            Ok(volume) => HttpResponse::Ok().json(volume::GetVolumeResponse {
+                volume,
                 err: "".to_string(),
             }),
-            Err(e) => HttpResponse::BadRequest().json(RpcError::from_str(&e)),
+            Err(e) => error_response(e),
         }
     }
     fn handle_volume_list(driver: Arc<T>) -> RpcResponse {
@@ -258,7 +286,7 @@ where
                 volumes: vols,
                 err: "".to_string(),
             }),
-            Err(e) => HttpResponse::BadRequest().json(RpcError::from_str(&e)),
+            Err(e) => error_response(e),
         }
     }
 }
@@ -0,0 +1,95 @@
+use std::fmt;
+
+/// Volume names are capped so that names derived from them, such as
+/// device-mapper names, stay within the kernel's limits.
+pub const MAX_VOLUME_NAME_LEN: usize = 100;
+const MIN_NAME_LEN: usize = 2;
+
+#[derive(Debug, PartialEq)]
+pub enum NameErrorKind {
+    TooShort(usize),
+    TooLong(usize),
+    InvalidStart(char),
+    InvalidCharacter(char),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct NameError {
+    pub name: String,
+    pub kind: NameErrorKind,
+}
+
+impl fmt::Display for NameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid name \"{}\": ", self.name)?;
+        match self.kind {
+            NameErrorKind::TooShort(min) => write!(f, "must be at least {} characters", min),
+            NameErrorKind::TooLong(max) => write!(f, "must be at most {} characters", max),
+            NameErrorKind::InvalidStart(c) => {
+                write!(f, "must start with a letter or digit, not {:?}", c)
+            }
+            NameErrorKind::InvalidCharacter(c) => write!(
+                f,
+                "may only contain letters, digits, '_', '.' and '-', not {:?}",
+                c
+            ),
+        }
+    }
+}
+
+/// Checks a name against Docker's volume name grammar,
+/// `[a-zA-Z0-9][a-zA-Z0-9_.-]+`, and a maximum length. Anything matching it
+/// is safe to use as a single path component.
+pub fn validate_name(name: &str, max_len: usize) -> Result<(), NameError> {
+    let error = |kind| {
+        Err(NameError {
+            name: name.to_string(),
+            kind,
+        })
+    };
+
+    let len = name.chars().count();
+    if len < MIN_NAME_LEN {
+        return error(NameErrorKind::TooShort(MIN_NAME_LEN));
+    }
+    if len > max_len {
+        return error(NameErrorKind::TooLong(max_len));
+    }
+
+    let mut chars = name.chars();
+    if let Some(first) = chars.next() {
+        if !first.is_ascii_alphanumeric() {
+            return error(NameErrorKind::InvalidStart(first));
+        }
+    }
+    if let Some(c) =
+        chars.find(|&c| !(c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-'))
+    {
+        return error(NameErrorKind::InvalidCharacter(c));
+    }
+
+    Ok(())
+}
+
+pub fn validate_volume_name(name: &str) -> Result<(), NameError> {
+    validate_name(name, MAX_VOLUME_NAME_LEN)
+}
+
+#[test]
+fn test_validate_volume_name() {
+    assert!(validate_volume_name("db-data").is_ok());
+    assert!(validate_volume_name("build_cache.v2").is_ok());
+    assert!(validate_volume_name("0a").is_ok());
+
+    let kind = |name: &str| validate_volume_name(name).unwrap_err().kind;
+    assert_eq!(kind(""), NameErrorKind::TooShort(2));
+    assert_eq!(kind("a"), NameErrorKind::TooShort(2));
+    assert_eq!(kind(".."), NameErrorKind::InvalidStart('.'));
+    assert_eq!(kind("../../etc"), NameErrorKind::InvalidStart('.'));
+    assert_eq!(kind("a/../../etc"), NameErrorKind::InvalidCharacter('/'));
+    assert_eq!(kind("with space"), NameErrorKind::InvalidCharacter(' '));
+    assert_eq!(
+        kind(&"a".repeat(MAX_VOLUME_NAME_LEN + 1)),
+        NameErrorKind::TooLong(MAX_VOLUME_NAME_LEN)
+    );
+}
@@ -1,4 +1,6 @@
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
 
 #[derive(Serialize, Deserialize, PartialEq)]
 pub enum Scope {
@@ -22,6 +24,15 @@ pub struct Volume {
     #[serde(rename = "Mountpoint")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub mountpoint: Option<String>,
+
+    /// When the volume was created, in RFC 3339 format.
+    #[serde(rename = "CreatedAt")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+
+    #[serde(rename = "Status")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<HashMap<String, Value>>,
 }
 
 #[derive(Serialize, Deserialize, PartialEq)]